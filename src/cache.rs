@@ -0,0 +1,213 @@
+use crate::config::Config;
+use lru::LruCache;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Durée de conservation retenue par le cache en mémoire lorsque le TTL demandé dépasse ce que l'horloge peut représenter
+const MAX_MEMORY_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+#[derive(Debug, Clone)]
+pub struct CacheError(pub String);
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cache error: {}", self.0)
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl From<redis::RedisError> for CacheError {
+    fn from(error: redis::RedisError) -> Self {
+        CacheError(error.to_string())
+    }
+}
+
+// Stockage utilisé pour mettre en cache les réponses de l'API
+#[rocket::async_trait]
+pub trait CacheBackend: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>, CacheError>;
+
+    // Enregistre une valeur qui expirera au bout de `ttl` secondes
+    async fn set(&self, key: &str, value: String, ttl: u64) -> Result<(), CacheError>;
+
+    async fn delete(&self, key: &str) -> Result<(), CacheError>;
+
+    // Liste les clés correspondant au motif (le caractère `*` remplace n'importe quelle suite de caractères)
+    async fn scan(&self, pattern: &str) -> Result<Vec<String>, CacheError>;
+
+    // Vérifie que le stockage répond (utilisé par /readyz)
+    async fn ping(&self) -> Result<(), CacheError> {
+        self.get("health:ping").await.map(|_| ())
+    }
+}
+
+pub type Cache = Arc<dyn CacheBackend>;
+
+// Choisit le stockage au démarrage : cache_backend = "redis" | "memory" (par défaut Redis si redis_url est défini)
+pub fn cache_from_config(config: &Config) -> Result<Cache, CacheError> {
+    let backend = config.cache_backend.clone().unwrap_or_else(|| {
+        if config.redis_url.is_some() { "redis" } else { "memory" }.to_string()
+    });
+
+    match backend.to_lowercase().as_str() {
+        "redis" => {
+            let redis_url = config
+                .redis_url
+                .as_deref()
+                .ok_or_else(|| CacheError("REDIS_URL must be set".into()))?;
+            Ok(Arc::new(RedisCache::new(redis_url)?))
+        }
+        "memory" => Ok(Arc::new(MemoryCache::new(config.cache_memory_capacity))),
+        other => Err(CacheError(format!("unknown cache backend `{}`", other))),
+    }
+}
+
+pub struct RedisCache {
+    client: redis::Client,
+    // Connexion partagée par toutes les requêtes, ouverte au premier appel et rouverte après une erreur réseau
+    connection: Mutex<Option<MultiplexedConnection>>,
+}
+
+impl RedisCache {
+    pub fn new(redis_url: &str) -> Result<Self, CacheError> {
+        Ok(Self {
+            client: redis::Client::open(redis_url)?,
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection, CacheError> {
+        if let Some(connection) = self.shared_connection().as_ref() {
+            return Ok(connection.clone());
+        }
+        let connection = self.client.get_multiplexed_async_connection().await?;
+        *self.shared_connection() = Some(connection.clone());
+        Ok(connection)
+    }
+
+    fn shared_connection(&self) -> std::sync::MutexGuard<'_, Option<MultiplexedConnection>> {
+        self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Oublie la connexion partagée si elle est coupée : la suivante sera ouverte au prochain appel
+    fn checked<T>(&self, result: redis::RedisResult<T>) -> Result<T, CacheError> {
+        if let Err(error) = &result {
+            if error.is_io_error() || error.is_connection_dropped() || error.is_connection_refusal() {
+                self.shared_connection().take();
+            }
+        }
+        Ok(result?)
+    }
+}
+
+#[rocket::async_trait]
+impl CacheBackend for RedisCache {
+    async fn get(&self, key: &str) -> Result<Option<String>, CacheError> {
+        let mut redis_conn = self.connection().await?;
+        self.checked(redis_conn.get(key).await)
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: u64) -> Result<(), CacheError> {
+        let mut redis_conn = self.connection().await?;
+        self.checked(redis_conn.set_ex(key, value, ttl).await)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        let mut redis_conn = self.connection().await?;
+        self.checked(redis_conn.del(key).await)
+    }
+
+    async fn scan(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        let mut redis_conn = self.connection().await?;
+        let mut keys = Vec::new();
+        let mut iter = self.checked(redis_conn.scan_match::<_, String>(pattern).await)?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+}
+
+// Cache LRU en mémoire, utile pour les petits déploiements et les tests (aucun serveur Redis nécessaire)
+pub struct MemoryCache {
+    entries: Mutex<LruCache<String, (String, Instant)>>, // Associe une clé à sa valeur et sa date d'expiration
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruCache<String, (String, Instant)>> {
+        // Un panic pendant la manipulation du cache ne doit pas le rendre inutilisable
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[rocket::async_trait]
+impl CacheBackend for MemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<String>, CacheError> {
+        let mut entries = self.lock();
+        match entries.get(key) {
+            Some((value, expires_at)) if *expires_at > Instant::now() => Ok(Some(value.clone())),
+            Some(_) => {
+                entries.pop(key);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: u64) -> Result<(), CacheError> {
+        let now = Instant::now();
+        let expires_at = now
+            .checked_add(Duration::from_secs(ttl))
+            .unwrap_or(now + MAX_MEMORY_TTL);
+        self.lock().put(key.to_string(), (value, expires_at));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        self.lock().pop(key);
+        Ok(())
+    }
+
+    async fn scan(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        let now = Instant::now();
+        Ok(self
+            .lock()
+            .iter()
+            .filter(|(key, (_, expires_at))| *expires_at > now && matches_pattern(pattern, key))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+}
+
+// Comparaison d'une clé avec un motif de type Redis ne gérant que le joker `*`
+fn matches_pattern(pattern: &str, key: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = key.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty(); // Pas de joker : la clé doit être identique au motif
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
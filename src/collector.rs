@@ -0,0 +1,75 @@
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::metrics::Metrics;
+use crate::queue_limits::QueueLimits;
+use crate::rate_limit::ApiKeyUsage;
+use crate::shutdown::Shutdown;
+use crate::utils::{api_request, ProxyContext, ProxyResponse, QueuedRequest};
+use crate::waiters::Waiters;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// Ressources permettant aux tâches de fond (historiques) d'interroger l'API comme un client du proxy : leurs requêtes
+// passent par le cache et la file d'attente, avec les clés API `collector_api_keys` et la priorité la plus basse
+#[derive(Clone)]
+pub struct Collector {
+    pub queue: mpsc::Sender<QueuedRequest>,
+    pub cache: Cache,
+    pub waiters: Arc<Waiters>,
+    pub metrics: Arc<Metrics>,
+    pub shutdown: Arc<Shutdown>,
+    pub api_key_usage: Arc<ApiKeyUsage>,
+    pub queue_limits: Arc<QueueLimits>,
+    pub config: Arc<Config>,
+}
+
+impl Collector {
+    // Requête GET sur l'API (`path` commence par "/"), avec la politique de cache de la route `route`
+    pub async fn fetch(&self, route: &str, path: &str) -> Result<ProxyResponse, ProxyError> {
+        let proxy = ProxyContext {
+            queue: &self.queue,
+            cache: &self.cache,
+            waiters: &self.waiters,
+            metrics: &self.metrics,
+            shutdown: &self.shutdown,
+            api_key_usage: &self.api_key_usage,
+            queue_limits: &self.queue_limits,
+            config: &self.config,
+            request_id: uuid::Uuid::new_v4().to_string(),
+            requested_priority: Some(self.config.min_priority),
+        };
+        let url = self.config.upstream_url(path);
+        let request = QueuedRequest::for_route(&proxy, route, url, self.config.collector_api_keys.clone(), None);
+        api_request(&proxy, request).await
+    }
+
+    // Lance `collect` toutes les `interval` secondes (0 pour ne jamais le lancer), jusqu'à l'arrêt du proxy
+    // Rien n'est collecté sans clé API dédiée aux collecteurs
+    pub fn spawn_every<F, Fut>(&self, name: &'static str, interval: u64, collect: F)
+    where
+        F: Fn(Collector) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        if interval == 0 || self.config.collector_api_keys.is_empty() {
+            return;
+        }
+        let collector = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(Duration::from_secs(interval));
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {}
+                    _ = collector.shutdown.triggered() => break,
+                }
+                tracing::debug!(collector = name, "collecting");
+                tokio::select! {
+                    _ = collect(collector.clone()) => {}
+                    _ = collector.shutdown.triggered() => break,
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,132 @@
+use crate::utils::{DEFAULT_CACHE_TIME, DEFAULT_STALE_TIME};
+use rocket::figment::providers::{Env, Format, Serialized, Toml};
+use rocket::figment::Figment;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Fichier de configuration chargé au démarrage (modifiable avec la variable d'environnement CONFIG_FILE)
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+// Variables d'environnement (.env) qui surchargent la configuration du fichier
+const ENV_OVERRIDES: [&str; 5] = [
+    "redis_url",
+    "cache_backend",
+    "cache_memory_capacity",
+    "api_key_rate_limit",
+    "log_level",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub upstream_base_url: String,
+    pub default_cache_time: u64,
+    pub default_stale_time: u64,
+    pub queue_size: usize,
+    pub request_timeout: u64,
+    pub upstream_timeout: u64,
+    pub worker_heartbeat_timeout: u64,
+    pub max_queue_age: u64,
+    pub upstream_success_max_age: Option<u64>,
+    pub shutdown_timeout: u64,
+    pub shutdown_retry_after: u64,
+    pub persist_queue: bool,
+    pub max_queued_requests: usize,
+    pub max_queued_per_client: usize,
+    pub min_priority: u8,
+    pub max_priority: u8,
+    pub priority_aging: u64,
+    pub collector_api_keys: Vec<String>,
+    pub history_path: String,
+    pub hdv_history_interval: u64,
+    pub playercount_history_interval: u64,
+    pub history_retention_days: u64,
+    pub redis_url: Option<String>,
+    pub cache_backend: Option<String>,
+    pub cache_memory_capacity: usize,
+    pub api_key_rate_limit: Option<String>,
+    pub api_key_profiles: HashMap<String, String>,
+    pub routes: HashMap<String, RoutePolicy>,
+    pub log_level: String,
+}
+
+// Politique de cache et de priorité d'une route du proxy
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoutePolicy {
+    pub cache_time: Option<u64>,
+    pub stale_time: Option<u64>,
+    pub priority: Option<u8>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut routes = HashMap::new();
+        routes.insert(
+            "playercount".to_string(),
+            RoutePolicy {
+                cache_time: Some(60),
+                ..Default::default()
+            },
+        );
+
+        Self {
+            upstream_base_url: "https://publicapi.nationsglory.fr".to_string(),
+            default_cache_time: DEFAULT_CACHE_TIME,
+            default_stale_time: DEFAULT_STALE_TIME,
+            queue_size: 100,
+            request_timeout: 30,
+            upstream_timeout: 10,
+            worker_heartbeat_timeout: 30,
+            max_queue_age: 120,
+            upstream_success_max_age: None,
+            shutdown_timeout: 10,
+            shutdown_retry_after: 30,
+            persist_queue: false,
+            max_queued_requests: 1000,
+            max_queued_per_client: 100,
+            min_priority: 0,
+            max_priority: 5,
+            priority_aging: 10,
+            collector_api_keys: Vec::new(),
+            history_path: "history.sqlite".to_string(),
+            hdv_history_interval: 3600,
+            playercount_history_interval: 300,
+            history_retention_days: 90,
+            redis_url: None,
+            cache_backend: None,
+            cache_memory_capacity: 10_000,
+            api_key_rate_limit: None,
+            api_key_profiles: HashMap::new(),
+            routes,
+            log_level: "info".to_string(),
+        }
+    }
+}
+
+impl Config {
+    // Valeurs par défaut, puis fichier TOML, puis variables d'environnement
+    pub fn load() -> Result<Self, Box<rocket::figment::Error>> {
+        let config_file =
+            std::env::var("CONFIG_FILE").unwrap_or_else(|_| DEFAULT_CONFIG_FILE.to_string());
+        Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::file(config_file))
+            .merge(Env::raw().only(&ENV_OVERRIDES))
+            .extract()
+            .map_err(Box::new)
+    }
+
+    pub fn upstream_url(&self, path: &str) -> String {
+        format!("{}{}", self.upstream_base_url.trim_end_matches('/'), path)
+    }
+
+    // Politique de la route, complétée par les valeurs par défaut
+    pub fn route(&self, name: &str) -> RoutePolicy {
+        let policy = self.routes.get(name).cloned().unwrap_or_default();
+        RoutePolicy {
+            cache_time: policy.cache_time.or(Some(self.default_cache_time)),
+            stale_time: policy.stale_time.or(Some(self.default_stale_time)),
+            priority: policy.priority.or(Some(0)),
+        }
+    }
+}
@@ -1,287 +1,536 @@
-use crate::utils::{api_request, get_cache_time_from_week_number, ApiKeys, QueuedRequest, RequestResponse};
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::health::Health;
+use crate::history::{
+    parse_time, player_count_series, player_counts_by_day, player_counts_by_hour_of_week, History, Resolution,
+};
+use crate::metrics::Metrics;
+use crate::models::{
+    validate, Country, CountryListEntry, HdvListing, NgIslandPage, Notation, PlanningEvent, PlayerCount, User,
+};
+use crate::rankings::rank_notations;
+use crate::rate_limit::ApiKeyUsage;
+use crate::server::Server;
+use crate::shutdown::Shutdown;
+use crate::utils::{
+    api_request, get_cache_time_from_week_number, ApiKeys, ProxyContext, ProxyResponse,
+    QueuedRequest,
+};
+use chrono::{DateTime, Utc};
+use rocket::form::error::ErrorKind;
+use rocket::form::Errors;
+use rocket::futures::stream::{self, StreamExt};
+use rocket::http::{ContentType, Status};
 use rocket::serde::json::Json;
 use rocket::{get, State};
+use serde::Deserialize;
 use serde_json::{json, Value};
-use tokio::sync::{broadcast, mpsc};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+// Période couverte par défaut par les historiques (en jours)
+const HISTORY_DEFAULT_DAYS: i64 = 7;
+// Nombre maximum de semaines demandées par /notations/range
+const NOTATIONS_RANGE_MAX_WEEKS: i64 = 52;
+// Nombre de semaines de /notations/range en attente dans la file en même temps
+const NOTATIONS_RANGE_CONCURRENCY: usize = 4;
 
 #[get("/planning?<server>&<month>&<year>")]
 pub async fn get_planning(
-    queue: &State<mpsc::Sender<QueuedRequest>>,
-    response_broadcast_tx: &State<broadcast::Sender<RequestResponse>>,
-    redis_client: &State<redis::Client>,
+    proxy: ProxyContext<'_>,
     api_keys: ApiKeys,
-    server: &str,
+    server: Result<Server, Errors<'_>>,
     month: &str,
     year: &str,
-) -> Result<Json<Value>, rocket::http::Status> {
+) -> Result<ProxyResponse, ProxyError> {
     if api_keys.0.is_empty() {
-        return Err(rocket::http::Status::BadRequest);
+        return Err(ProxyError::MissingApiKey);
     }
 
-    let server = server.to_lowercase();
+    let server = server.map_err(|_| ProxyError::UnknownServer)?;
     let month = month.to_lowercase();
     let year = year.to_lowercase();
 
-    let url = format!(
-        "https://publicapi.nationsglory.fr/planning?server={}&month={}&year={}",
+    let url = proxy.config.upstream_url(&format!(
+        "/planning?server={}&month={}&year={}",
         server, month, year
-    );
+    ));
 
-    let request = QueuedRequest {
-        url,
-        method: "GET".to_string(),
-        api_keys: api_keys.0,
-        cache_time: None,
-    };
+    let request = QueuedRequest::for_route(&proxy, "planning", url, api_keys.0, None);
 
-    api_request(queue, redis_client, request, response_broadcast_tx).await
+    let response = api_request(&proxy, request).await;
+    validate::<Vec<PlanningEvent>>(&proxy, "planning", &response);
+    response
 }
 
 #[get("/playercount")]
 pub async fn get_playercount(
-    queue: &State<mpsc::Sender<QueuedRequest>>,
-    response_broadcast_tx: &State<broadcast::Sender<RequestResponse>>,
-    redis_client: &State<redis::Client>,
+    proxy: ProxyContext<'_>,
     api_keys: ApiKeys,
-) -> Result<Json<Value>, rocket::http::Status> {
+) -> Result<ProxyResponse, ProxyError> {
     if api_keys.0.is_empty() {
-        return Err(rocket::http::Status::BadRequest);
+        return Err(ProxyError::MissingApiKey);
     }
 
-    let url = "https://publicapi.nationsglory.fr/playercount".to_string();
+    let url = proxy.config.upstream_url("/playercount");
 
-    let request = QueuedRequest {
-        url,
-        method: "GET".to_string(),
-        api_keys: api_keys.0,
-        cache_time: Some(60),
-    };
+    let request = QueuedRequest::for_route(&proxy, "playercount", url, api_keys.0, None);
+
+    let response = api_request(&proxy, request).await;
+    validate::<PlayerCount>(&proxy, "playercount", &response);
+    response
+}
 
-    api_request(queue, redis_client, request, response_broadcast_tx).await
+// Serveurs acceptés par le proxy et leur nombre de joueurs connectés (null si /playercount ne l'indique pas)
+// Les en-têtes (X-Cache, Age...) sont ceux de la réponse de /playercount
+#[get("/servers")]
+pub async fn get_servers(
+    proxy: ProxyContext<'_>,
+    api_keys: ApiKeys,
+) -> Result<ProxyResponse, ProxyError> {
+    let mut response = get_playercount(proxy, api_keys).await?;
+    // Une erreur de l'API ne doit pas être présentée comme une liste de serveurs sans joueurs
+    if !(200..300).contains(&response.status) {
+        return Ok(response);
+    }
+    let player_counts: BTreeMap<String, u64> = PlayerCount::deserialize(&response.body)
+        .map(|player_count| {
+            player_count
+                .servers
+                .into_iter()
+                .map(|(server, count)| (server.to_lowercase(), count))
+                .collect()
+        })
+        .unwrap_or_default();
+    let servers = Server::ALL
+        .iter()
+        .map(|server| {
+            json!({
+                "name": server,
+                "player_count": player_counts.get(server.as_str()),
+            })
+        })
+        .collect();
+    response.body = Value::Array(servers);
+    Ok(response)
 }
 
 #[get("/hdv/<server>/list")]
 pub async fn get_hdv(
-    queue: &State<mpsc::Sender<QueuedRequest>>,
-    response_broadcast_tx: &State<broadcast::Sender<RequestResponse>>,
-    redis_client: &State<redis::Client>,
+    proxy: ProxyContext<'_>,
     api_keys: ApiKeys,
-    server: &str,
-) -> Result<Json<Value>, rocket::http::Status> {
+    server: Result<Server, &str>,
+) -> Result<ProxyResponse, ProxyError> {
     if api_keys.0.is_empty() {
-        return Err(rocket::http::Status::BadRequest);
+        return Err(ProxyError::MissingApiKey);
     }
 
-    let server = server.to_lowercase();
+    let server = server.map_err(|_| ProxyError::UnknownServer)?;
 
-    let url = format!("https://publicapi.nationsglory.fr/hdv/{}/list", server);
+    let url = proxy.config.upstream_url(&format!("/hdv/{}/list", server));
 
-    let request = QueuedRequest {
-        url,
-        method: "GET".to_string(),
-        api_keys: api_keys.0,
-        cache_time: None,
-    };
+    let request = QueuedRequest::for_route(&proxy, "hdv", url, api_keys.0, None);
 
-    api_request(queue, redis_client, request, response_broadcast_tx).await
+    let response = api_request(&proxy, request).await;
+    validate::<Vec<HdvListing>>(&proxy, "hdv", &response);
+    response
 }
 
-#[get("/notations?<week>", rank = 2)]
-pub async fn get_all_notations(
-    queue: &State<mpsc::Sender<QueuedRequest>>,
-    response_broadcast_tx: &State<broadcast::Sender<RequestResponse>>,
-    redis_client: &State<redis::Client>,
+// Notations d'une semaine, de tous les serveurs ou d'un seul, éventuellement filtrées par pays
+#[get("/notations?<week>&<server>&<country>")]
+pub async fn get_notations(
+    proxy: ProxyContext<'_>,
     api_keys: ApiKeys,
     week: &str,
-) -> Result<Json<Value>, rocket::http::Status> {
+    server: Result<Server, Errors<'_>>,
+    country: Option<String>,
+) -> Result<ProxyResponse, ProxyError> {
     if api_keys.0.is_empty() {
-        return Err(rocket::http::Status::BadRequest);
+        return Err(ProxyError::MissingApiKey);
     }
 
     let week = week.to_lowercase();
+    let server = match server {
+        Ok(server) => Some(server),
+        // Rocket signale un paramètre absent comme une erreur : sans serveur, on demande les notations de tous les serveurs
+        Err(errors) if errors.iter().all(|error| matches!(error.kind, ErrorKind::Missing)) => None,
+        Err(_) => return Err(ProxyError::UnknownServer),
+    };
+    let country = country.map(|c| c.to_lowercase());
 
-    let url = format!(
-        "https://publicapi.nationsglory.fr/notations?week={}",
-        week
-    );
+    let url = match server {
+        Some(server) => proxy.config.upstream_url(&format!("/notations?week={}&server={}", week, server)),
+        None => proxy.config.upstream_url(&format!("/notations?week={}", week)),
+    };
 
     let week_number = week.parse::<i64>();
     let cache_time = get_cache_time_from_week_number(week_number.unwrap_or(-1));
 
-    let request = QueuedRequest {
-        url,
-        method: "GET".to_string(),
-        api_keys: api_keys.0,
-        cache_time
-    };
-
-    api_request(queue, redis_client, request, response_broadcast_tx).await
+    let request = QueuedRequest::for_route(&proxy, "notations", url, api_keys.0, cache_time);
+
+    let response = api_request(&proxy, request).await;
+    validate::<Vec<Notation>>(&proxy, "notations", &response);
+
+    if let Some(country) = country {
+        if let Ok(mut response) = response {
+            if let Some(notations) = response.body.as_array() {
+                // On garde les notations telles que renvoyées par l'API, le modèle ne sert qu'à lire le pays
+                let filtered_notations = notations
+                    .iter()
+                    .filter(|n| {
+                        Notation::deserialize(*n).is_ok_and(|notation| notation.is_country(&country))
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+                response.body = Value::Array(filtered_notations);
+            }
+            return Ok(response); // Si la réponse n'est pas un tableau, on la renvoie telle quelle (c'est que le json est inattendu)
+        }
+    }
+    response // Soit si country est None, soit si la requête à échouer (Err)
 }
 
-#[get("/notations?<week>&<server>&<country>", rank = 1)]
-pub async fn get_notations(
-    queue: &State<mpsc::Sender<QueuedRequest>>,
-    response_broadcast_tx: &State<broadcast::Sender<RequestResponse>>,
-    redis_client: &State<redis::Client>,
+// Notations de plusieurs semaines, demandées par la file d'attente quelques-unes à la fois, avec le classement des pays
+// Les semaines passées profitent du cache de deux mois de get_cache_time_from_week_number
+#[get("/notations/range?<server>&<from_week>&<to_week>&<country>")]
+pub async fn get_notations_range(
+    proxy: ProxyContext<'_>,
     api_keys: ApiKeys,
-    week: &str,
-    server: &str,
+    server: Result<Server, Errors<'_>>,
+    from_week: Result<i64, Errors<'_>>,
+    to_week: Result<i64, Errors<'_>>,
     country: Option<String>,
-) -> Result<Json<Value>, rocket::http::Status> {
+) -> Result<ProxyResponse, ProxyError> {
     if api_keys.0.is_empty() {
-        return Err(rocket::http::Status::BadRequest);
+        return Err(ProxyError::MissingApiKey);
     }
 
-    let week = week.to_lowercase();
-    let server = server.to_lowercase();
-    let country = country.map(|c| c.to_lowercase());
-
-    let url = format!(
-        "https://publicapi.nationsglory.fr/notations?week={}&server={}",
-        week, server,
-    );
-
-    let week_number = week.parse::<i64>();
-    let cache_time = get_cache_time_from_week_number(week_number.unwrap_or(-1));
-
-    let request = QueuedRequest {
-        url,
-        method: "GET".to_string(),
-        api_keys: api_keys.0,
-        cache_time,
+    let server = server.map_err(|_| ProxyError::UnknownServer)?;
+    let invalid = |name: &str| ProxyError::InvalidParameter {
+        name: name.to_string(),
+    };
+    // Les semaines sont comptées depuis 1970 : une semaine négative n'existe pas
+    let from_week = from_week.ok().filter(|week| *week >= 0).ok_or_else(|| invalid("from_week"))?;
+    let to_week = to_week.ok().filter(|week| *week >= 0).ok_or_else(|| invalid("to_week"))?;
+    if to_week < from_week {
+        return Err(invalid("to_week"));
+    }
+    if to_week.checked_sub(from_week).is_none_or(|weeks| weeks >= NOTATIONS_RANGE_MAX_WEEKS) {
+        return Err(ProxyError::RangeTooLarge {
+            max_weeks: NOTATIONS_RANGE_MAX_WEEKS,
+        });
+    }
+    // Chaque semaine en attente occupe une place du client dans la file : on n'en demande jamais plus que sa limite
+    let concurrency = match proxy.config.max_queued_per_client {
+        0 => NOTATIONS_RANGE_CONCURRENCY,
+        limit => NOTATIONS_RANGE_CONCURRENCY.min(limit),
     };
 
-    let response = api_request(queue, redis_client, request, response_broadcast_tx).await;
-
-    if country.is_some() {
-        let country = country.unwrap();
-        if let Ok(response) = response {
-            if let Some(data) = response.get("data") {
-                if let Some(notation) = data.as_array() {
-                    let filtered_notations = notation
-                        .into_iter()
-                        .filter(|n| n["pays"].as_str().unwrap().to_lowercase() == country)
-                        .collect::<Vec<_>>();
-                    let mut response = response.as_object().unwrap().clone();
-                    response.insert(
-                        "data".to_string(),
-                        serde_json::to_value(filtered_notations).unwrap(),
-                    );
-                    return Ok(Json(json!(response)));
-                }
-            }
-            return Ok(response); // Si la réponse n'est pas un tableau, on la renvoie telle quelle (c'est que le json est inattendu)
+    let requests = (from_week..=to_week).map(|week| {
+        let url = proxy.config.upstream_url(&format!("/notations?week={}&server={}", week, server));
+        let cache_time = get_cache_time_from_week_number(week);
+        let request = QueuedRequest::for_route(&proxy, "notations", url, api_keys.0.clone(), cache_time);
+        let proxy = &proxy;
+        async move {
+            let response = api_request(proxy, request).await;
+            validate::<Vec<Notation>>(proxy, "notations", &response);
+            (week, response)
+        }
+    });
+
+    let mut weeks = Vec::new();
+    let mut responses = stream::iter(requests).buffered(concurrency);
+    while let Some((week, response)) = responses.next().await {
+        let response = response?;
+        // Une semaine en erreur chez l'API fausserait le classement : l'erreur est renvoyée telle quelle
+        if !(200..300).contains(&response.status) {
+            return Ok(response);
         }
+        let notations = response
+            .body
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|notation| Notation::deserialize(notation).ok())
+            .collect();
+        weeks.push((week, notations));
     }
-    response // Soit si country est None, soit si la requête à échouer (Err)
+
+    let mut rankings = rank_notations(&weeks);
+    if let Some(country) = country {
+        rankings.retain(|ranking| ranking.country.to_lowercase() == country.to_lowercase());
+    }
+    Ok(ProxyResponse::ok(json!({
+        "server": server,
+        "from_week": from_week,
+        "to_week": to_week,
+        "countries": rankings,
+    })))
 }
 
 #[get("/country/<server>/<country>", rank = 2)]
 pub async fn get_country(
-    queue: &State<mpsc::Sender<QueuedRequest>>,
-    response_broadcast_tx: &State<broadcast::Sender<RequestResponse>>,
-    redis_client: &State<redis::Client>,
+    proxy: ProxyContext<'_>,
     api_keys: ApiKeys,
-    server: &str,
+    server: Result<Server, &str>,
     country: &str,
-) -> Result<Json<Value>, rocket::http::Status> {
+) -> Result<ProxyResponse, ProxyError> {
     if api_keys.0.is_empty() {
-        return Err(rocket::http::Status::BadRequest);
+        return Err(ProxyError::MissingApiKey);
     }
 
-    let server = server.to_lowercase();
+    let server = server.map_err(|_| ProxyError::UnknownServer)?;
     let country = country.to_lowercase();
 
-    let url = format!(
-        "https://publicapi.nationsglory.fr/country/{}/{}",
-        server, country
-    );
+    let url = proxy.config.upstream_url(&format!("/country/{}/{}", server, country));
 
-    let request = QueuedRequest {
-        url,
-        method: "GET".to_string(),
-        api_keys: api_keys.0,
-        cache_time: None,
-    };
+    let request = QueuedRequest::for_route(&proxy, "country", url, api_keys.0, None);
 
-    api_request(queue, redis_client, request, response_broadcast_tx).await
+    let response = api_request(&proxy, request).await;
+    validate::<Country>(&proxy, "country", &response);
+    response
 }
 
 #[get("/country/list/<server>", rank = 1)]
 pub async fn get_country_list(
-    queue: &State<mpsc::Sender<QueuedRequest>>,
-    response_broadcast_tx: &State<broadcast::Sender<RequestResponse>>,
-    redis_client: &State<redis::Client>,
+    proxy: ProxyContext<'_>,
     api_keys: ApiKeys,
-    server: &str,
-) -> Result<Json<Value>, rocket::http::Status> {
+    server: Result<Server, &str>,
+) -> Result<ProxyResponse, ProxyError> {
     if api_keys.0.is_empty() {
-        return Err(rocket::http::Status::BadRequest);
+        return Err(ProxyError::MissingApiKey);
     }
 
-    let server = server.to_lowercase();
+    let server = server.map_err(|_| ProxyError::UnknownServer)?;
 
-    let url = format!("https://publicapi.nationsglory.fr/country/list/{}", server);
+    let url = proxy.config.upstream_url(&format!("/country/list/{}", server));
 
-    let request = QueuedRequest {
-        url,
-        method: "GET".to_string(),
-        api_keys: api_keys.0,
-        cache_time: None,
-    };
+    let request = QueuedRequest::for_route(&proxy, "country_list", url, api_keys.0, None);
 
-    api_request(queue, redis_client, request, response_broadcast_tx).await
+    let response = api_request(&proxy, request).await;
+    validate::<Vec<CountryListEntry>>(&proxy, "country_list", &response);
+    response
 }
 
 #[get("/user/<username>")]
 pub async fn get_user(
-    queue: &State<mpsc::Sender<QueuedRequest>>,
-    response_broadcast_tx: &State<broadcast::Sender<RequestResponse>>,
-    redis_client: &State<redis::Client>,
+    proxy: ProxyContext<'_>,
     api_keys: ApiKeys,
     username: &str,
-) -> Result<Json<Value>, rocket::http::Status> {
+) -> Result<ProxyResponse, ProxyError> {
     if api_keys.0.is_empty() {
-        return Err(rocket::http::Status::BadRequest);
+        return Err(ProxyError::MissingApiKey);
     }
 
     //let username = username.to_lowercase(); // TODO: Dans l'attente d'un fix de l'API Nations Glory (les skills sont actuellement non fonctionnel si en lower case)
 
-    let url = format!("https://publicapi.nationsglory.fr/user/{}", username);
+    let url = proxy.config.upstream_url(&format!("/user/{}", username));
 
-    let request = QueuedRequest {
-        url,
-        method: "GET".to_string(),
-        api_keys: api_keys.0,
-        cache_time: None,
-    };
+    let request = QueuedRequest::for_route(&proxy, "user", url, api_keys.0, None);
 
-    api_request(queue, redis_client, request, response_broadcast_tx).await
+    let response = api_request(&proxy, request).await;
+    validate::<User>(&proxy, "user", &response);
+    response
 }
 
 #[get("/ngisland/list?<page>")]
 pub async fn get_ngisland_list(
-    queue: &State<mpsc::Sender<QueuedRequest>>,
-    response_broadcast_tx: &State<broadcast::Sender<RequestResponse>>,
-    redis_client: &State<redis::Client>,
+    proxy: ProxyContext<'_>,
     api_keys: ApiKeys,
     page: &str,
-) -> Result<Json<Value>, rocket::http::Status> {
+) -> Result<ProxyResponse, ProxyError> {
     if api_keys.0.is_empty() {
-        return Err(rocket::http::Status::BadRequest);
+        return Err(ProxyError::MissingApiKey);
     }
 
-    let url = format!(
-        "https://publicapi.nationsglory.fr/ngisland/list?page={}",
-        page
-    );
+    let url = proxy.config.upstream_url(&format!("/ngisland/list?page={}", page));
+
+    let request = QueuedRequest::for_route(&proxy, "ngisland_list", url, api_keys.0, None);
+
+    let response = api_request(&proxy, request).await;
+    validate::<NgIslandPage>(&proxy, "ngisland_list", &response);
+    response
+}
+
+// Période demandée à un historique : les 7 derniers jours par défaut
+fn history_window(from: Option<&str>, to: Option<&str>) -> Result<(DateTime<Utc>, DateTime<Utc>), ProxyError> {
+    let to = parse_time("to", to)?.unwrap_or_else(Utc::now);
+    let from = parse_time("from", from)?.unwrap_or(to - chrono::Duration::days(HISTORY_DEFAULT_DAYS));
+    Ok((from, to))
+}
 
-    let request = QueuedRequest {
-        url,
-        method: "GET".to_string(),
-        api_keys: api_keys.0,
-        cache_time: None,
+// Les erreurs de la base locale ne concernent pas le client : elles sont journalisées et renvoyées en 500
+fn history_error(error: rusqlite::Error) -> ProxyError {
+    tracing::error!(%error, "history query failed");
+    ProxyError::Internal
+}
+
+// Historique du prix d'un objet à l'HDV, relevé par relevé
+#[get("/hdv/<server>/history?<item>&<from>&<to>")]
+pub async fn get_hdv_history(
+    history: &State<Arc<History>>,
+    server: Result<Server, &str>,
+    item: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<ProxyResponse, ProxyError> {
+    let server = server.map_err(|_| ProxyError::UnknownServer)?;
+    let (from, to) = history_window(from, to)?;
+    let item = item.trim().to_lowercase();
+    let points = {
+        let item = item.clone();
+        history
+            .run(move |history| history.hdv_price_history(server, &item, from, to))
+            .await
+            .map_err(history_error)?
+    };
+    Ok(ProxyResponse::ok(json!({
+        "server": server,
+        "item": item,
+        "from": from,
+        "to": to,
+        "points": points,
+    })))
+}
+
+// Prix minimum, médian et maximum d'un objet à l'HDV sur la période
+#[get("/hdv/<server>/stats?<item>&<from>&<to>")]
+pub async fn get_hdv_stats(
+    history: &State<Arc<History>>,
+    server: Result<Server, &str>,
+    item: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<ProxyResponse, ProxyError> {
+    let server = server.map_err(|_| ProxyError::UnknownServer)?;
+    let (from, to) = history_window(from, to)?;
+    let item = item.trim().to_lowercase();
+    let stats = {
+        let item = item.clone();
+        history
+            .run(move |history| history.hdv_price_stats(server, &item, from, to))
+            .await
+            .map_err(history_error)?
     };
+    Ok(ProxyResponse::ok(json!({
+        "server": server,
+        "item": item,
+        "from": from,
+        "to": to,
+        "listings": stats.listings,
+        "min": stats.min,
+        "median": stats.median,
+        "max": stats.max,
+    })))
+}
+
+// Nombre d'annonces à l'HDV (de tous les objets, ou d'un seul) relevé par relevé
+#[get("/hdv/<server>/volume?<item>&<from>&<to>")]
+pub async fn get_hdv_volume(
+    history: &State<Arc<History>>,
+    server: Result<Server, &str>,
+    item: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<ProxyResponse, ProxyError> {
+    let server = server.map_err(|_| ProxyError::UnknownServer)?;
+    let (from, to) = history_window(from, to)?;
+    let item = item.map(|item| item.trim().to_lowercase());
+    let points = {
+        let item = item.clone();
+        history
+            .run(move |history| history.hdv_volume(server, item.as_deref(), from, to))
+            .await
+            .map_err(history_error)?
+    };
+    Ok(ProxyResponse::ok(json!({
+        "server": server,
+        "item": item,
+        "from": from,
+        "to": to,
+        "points": points,
+    })))
+}
 
-    api_request(queue, redis_client, request, response_broadcast_tx).await
+// Historique du nombre de joueurs d'un serveur à la résolution demandée ("raw", "hour" ou "day"),
+// avec le pic et la moyenne par jour et par heure de la semaine (UTC)
+#[get("/playercount/history?<server>&<from>&<to>&<resolution>")]
+pub async fn get_playercount_history(
+    history: &State<Arc<History>>,
+    server: Result<Server, Errors<'_>>,
+    from: Option<&str>,
+    to: Option<&str>,
+    resolution: Option<&str>,
+) -> Result<ProxyResponse, ProxyError> {
+    let server = server.map_err(|_| ProxyError::UnknownServer)?;
+    let resolution = Resolution::parse(resolution)?;
+    let (from, to) = history_window(from, to)?;
+    let samples = history
+        .run(move |history| history.player_counts(server, from, to))
+        .await
+        .map_err(history_error)?;
+    let points: Vec<Value> = player_count_series(&samples, resolution)
+        .into_iter()
+        .map(|(time, stats)| json!({ "time": time, "peak": stats.peak, "avg": stats.avg, "samples": stats.samples }))
+        .collect();
+    let daily: Vec<Value> = player_counts_by_day(&samples)
+        .into_iter()
+        .map(|(date, stats)| json!({ "date": date, "peak": stats.peak, "avg": stats.avg, "samples": stats.samples }))
+        .collect();
+    let hour_of_week: Vec<Value> = player_counts_by_hour_of_week(&samples)
+        .into_iter()
+        .map(|((weekday, hour), stats)| {
+            json!({
+                "weekday": weekday.to_string().to_lowercase(),
+                "hour": hour,
+                "peak": stats.peak,
+                "avg": stats.avg,
+                "samples": stats.samples,
+            })
+        })
+        .collect();
+    Ok(ProxyResponse::ok(json!({
+        "server": server,
+        "from": from,
+        "to": to,
+        "resolution": resolution.as_str(),
+        "points": points,
+        "daily": daily,
+        "hour_of_week": hour_of_week,
+    })))
+}
+
+// Métriques du proxy au format texte de Prometheus
+#[get("/metrics")]
+pub fn get_metrics(
+    metrics: &State<Arc<Metrics>>,
+    api_key_usage: &State<Arc<ApiKeyUsage>>,
+) -> (ContentType, String) {
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    (content_type, metrics.render(api_key_usage))
+}
+
+// Liveness : le processus répond
+#[get("/healthz")]
+pub fn get_healthz() -> Json<Value> {
+    Json(json!({"status": "ok"}))
+}
+
+// Readiness : le cache, le worker, la file d'attente et l'API sont en état de servir les requêtes (503 sinon)
+#[get("/readyz")]
+pub async fn get_readyz(
+    health: &State<Arc<Health>>,
+    cache: &State<Cache>,
+    config: &State<Config>,
+    shutdown: &State<Arc<Shutdown>>,
+) -> (Status, Json<Value>) {
+    let (ready, report) = health.readiness(cache, config, shutdown).await;
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, Json(report))
 }
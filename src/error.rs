@@ -0,0 +1,134 @@
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::{catch, Request};
+use serde_json::{json, Value};
+use std::fmt;
+
+// Erreurs renvoyées aux clients du proxy, sous la forme {"error", "code", "upstream_status"}
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyError {
+    MissingApiKey,
+    UnknownServer,
+    InvalidParameter { name: String },
+    RangeTooLarge { max_weeks: i64 },
+    InvalidApiKey { upstream_status: Option<u16> },
+    QueueUnavailable,
+    UpstreamUnavailable,
+    InvalidUpstreamResponse { upstream_status: u16 },
+    Timeout,
+    ShuttingDown { retry_after: u64 },
+    QueueFull { retry_after: u64 },
+    Internal,
+    Http(Status), // Erreurs produites par Rocket lui-même (route inconnue, paramètre invalide...)
+}
+
+impl ProxyError {
+    pub fn status(&self) -> Status {
+        match self {
+            ProxyError::MissingApiKey => Status::BadRequest,
+            ProxyError::UnknownServer => Status::BadRequest,
+            ProxyError::InvalidParameter { .. } => Status::BadRequest,
+            ProxyError::RangeTooLarge { .. } => Status::BadRequest,
+            ProxyError::InvalidApiKey { .. } => Status::Unauthorized,
+            ProxyError::QueueUnavailable => Status::ServiceUnavailable,
+            ProxyError::UpstreamUnavailable => Status::BadGateway,
+            ProxyError::InvalidUpstreamResponse { .. } => Status::BadGateway,
+            ProxyError::Timeout => Status::GatewayTimeout,
+            ProxyError::ShuttingDown { .. } => Status::ServiceUnavailable,
+            ProxyError::QueueFull { .. } => Status::TooManyRequests,
+            ProxyError::Internal => Status::InternalServerError,
+            ProxyError::Http(status) => *status,
+        }
+    }
+
+    pub fn code(&self) -> String {
+        match self {
+            ProxyError::MissingApiKey => "missing_api_key".to_string(),
+            ProxyError::UnknownServer => "unknown_server".to_string(),
+            ProxyError::InvalidParameter { .. } => "invalid_parameter".to_string(),
+            ProxyError::RangeTooLarge { .. } => "range_too_large".to_string(),
+            ProxyError::InvalidApiKey { .. } => "invalid_api_key".to_string(),
+            ProxyError::QueueUnavailable => "queue_unavailable".to_string(),
+            ProxyError::UpstreamUnavailable => "upstream_unavailable".to_string(),
+            ProxyError::InvalidUpstreamResponse { .. } => "invalid_upstream_response".to_string(),
+            ProxyError::Timeout => "timeout".to_string(),
+            ProxyError::ShuttingDown { .. } => "shutting_down".to_string(),
+            ProxyError::QueueFull { .. } => "queue_full".to_string(),
+            ProxyError::Internal => "internal_error".to_string(),
+            ProxyError::Http(status) => status
+                .reason_lossy()
+                .to_lowercase()
+                .replace([' ', '-'], "_"),
+        }
+    }
+
+    pub fn upstream_status(&self) -> Option<u16> {
+        match self {
+            ProxyError::InvalidApiKey { upstream_status } => *upstream_status,
+            ProxyError::InvalidUpstreamResponse { upstream_status } => Some(*upstream_status),
+            _ => None,
+        }
+    }
+
+    // Délai (en secondes) après lequel le client peut réessayer, renvoyé dans l'en-tête Retry-After
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            ProxyError::ShuttingDown { retry_after } => Some(*retry_after),
+            ProxyError::QueueFull { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "error": self.to_string(),
+            "code": self.code(),
+            "upstream_status": self.upstream_status(),
+        })
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::MissingApiKey => write!(f, "Missing API key in the Authorization header"),
+            ProxyError::UnknownServer => write!(f, "Unknown NationsGlory server"),
+            ProxyError::InvalidParameter { name } => write!(f, "Invalid value for the `{}` parameter", name),
+            ProxyError::RangeTooLarge { max_weeks } => write!(f, "A range covers at most {} weeks", max_weeks),
+            ProxyError::InvalidApiKey { .. } => write!(f, "Invalid API key"),
+            ProxyError::QueueUnavailable => write!(f, "The request queue is unavailable"),
+            ProxyError::UpstreamUnavailable => write!(f, "API request failed"),
+            ProxyError::InvalidUpstreamResponse { .. } => write!(f, "Failed to parse response"),
+            ProxyError::Timeout => write!(f, "The NationsGlory API did not answer in time"),
+            ProxyError::ShuttingDown { .. } => write!(f, "The proxy is shutting down"),
+            ProxyError::QueueFull { .. } => write!(f, "Too many requests are waiting for the NationsGlory API"),
+            ProxyError::Internal => write!(f, "Internal error"),
+            ProxyError::Http(status) => write!(f, "{}", status.reason_lossy()),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl<'r> Responder<'r, 'static> for ProxyError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = (self.status(), Json(self.to_json())).respond_to(req)?;
+        if let Some(retry_after) = self.retry_after() {
+            response.set_header(Header::new("Retry-After", retry_after.to_string()));
+        }
+        Ok(response)
+    }
+}
+
+// Les gardes de requête qui échouent y déposent leur erreur pour que le catcher la renvoie telle quelle
+pub fn set_request_error(req: &Request<'_>, error: ProxyError) {
+    req.local_cache(|| Some(error));
+}
+
+#[catch(default)]
+pub fn default_catcher(status: Status, req: &Request<'_>) -> ProxyError {
+    req.local_cache(|| None::<ProxyError>)
+        .clone()
+        .unwrap_or(ProxyError::Http(status))
+}
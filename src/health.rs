@@ -0,0 +1,111 @@
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::shutdown::Shutdown;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Intervalle auquel le worker signale qu'il est toujours en vie, même sans requête à traiter
+pub const WORKER_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+// État du worker et de l'API, mis à jour par le worker et lu par /readyz
+// Les dates sont stockées en millisecondes depuis l'epoch UNIX (0 = jamais)
+#[derive(Debug, Default)]
+pub struct Health {
+    worker_heartbeat: AtomicU64,
+    oldest_queued_at: AtomicU64,
+    queue_depth: AtomicU64,
+    last_upstream_success: AtomicU64,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Temps écoulé (en secondes) depuis une date enregistrée, ou None si elle ne l'a jamais été
+fn elapsed_secs(timestamp: &AtomicU64) -> Option<f64> {
+    match timestamp.load(Ordering::Relaxed) {
+        0 => None,
+        timestamp => Some(now_millis().saturating_sub(timestamp) as f64 / 1000.0),
+    }
+}
+
+impl Health {
+    // Créé au lancement du worker : on considère qu'il vient de donner signe de vie
+    pub fn new() -> Self {
+        let health = Self::default();
+        health.worker_heartbeat.store(now_millis(), Ordering::Relaxed);
+        health
+    }
+
+    // Le worker est en vie : il indique aussi l'état de sa file d'attente (attente de la plus ancienne requête)
+    pub fn worker_heartbeat(&self, queue_depth: usize, oldest_wait: Option<Duration>) {
+        let now = now_millis();
+        self.worker_heartbeat.store(now, Ordering::Relaxed);
+        self.queue_depth.store(queue_depth as u64, Ordering::Relaxed);
+        let oldest_queued_at = oldest_wait.map_or(0, |wait| now.saturating_sub(wait.as_millis() as u64));
+        self.oldest_queued_at.store(oldest_queued_at, Ordering::Relaxed);
+    }
+
+    pub fn upstream_success(&self) {
+        self.last_upstream_success.store(now_millis(), Ordering::Relaxed);
+    }
+
+    // Vérifie le cache, le worker, la file d'attente et (si configuré) la dernière réponse de l'API
+    // Renvoie l'état global et le détail de chaque vérification
+    pub async fn readiness(&self, cache: &Cache, config: &Config, shutdown: &Shutdown) -> (bool, Value) {
+        let cache_check = match cache.ping().await {
+            Ok(()) => json!({"status": "ok"}),
+            Err(error) => json!({"status": "error", "error": error.to_string()}),
+        };
+
+        let heartbeat_age = elapsed_secs(&self.worker_heartbeat);
+        let worker_ok = heartbeat_age.is_some_and(|age| age <= config.worker_heartbeat_timeout as f64);
+        let worker_check = json!({
+            "status": if worker_ok { "ok" } else { "error" },
+            "last_heartbeat_secs": heartbeat_age,
+        });
+
+        let queue_age = elapsed_secs(&self.oldest_queued_at);
+        let queue_ok = queue_age.is_none_or(|age| age <= config.max_queue_age as f64);
+        let queue_check = json!({
+            "status": if queue_ok { "ok" } else { "error" },
+            "depth": self.queue_depth.load(Ordering::Relaxed),
+            "oldest_wait_secs": queue_age,
+        });
+
+        let last_success = elapsed_secs(&self.last_upstream_success);
+        let upstream_ok = match config.upstream_success_max_age {
+            Some(max_age) => last_success.is_some_and(|age| age <= max_age as f64),
+            None => true, // Vérification désactivée : le proxy reste prêt même sans appel récent à l'API
+        };
+        let upstream_check = json!({
+            "status": if upstream_ok { "ok" } else { "error" },
+            "last_success_secs": last_success,
+        });
+
+        let cache_ok = cache_check["status"] == "ok";
+        let shutting_down = shutdown.is_triggered();
+        let ready = cache_ok && worker_ok && queue_ok && upstream_ok && !shutting_down;
+        let status = if shutting_down {
+            "shutting_down"
+        } else if ready {
+            "ok"
+        } else {
+            "degraded"
+        };
+        let report = json!({
+            "status": status,
+            "checks": {
+                "cache": cache_check,
+                "worker": worker_check,
+                "queue": queue_check,
+                "upstream": upstream_check,
+            },
+        });
+        (ready, report)
+    }
+}
@@ -0,0 +1,536 @@
+use crate::collector::Collector;
+use crate::error::ProxyError;
+use crate::models::{HdvListing, PlayerCount};
+use crate::server::Server;
+use crate::utils::ProxyResponse;
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Utc, Weekday};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// Historiques collectés en arrière-plan, stockés dans une base SQLite locale (`history_path`)
+// Les dates sont stockées en secondes depuis l'epoch UNIX. Les requêtes sont bloquantes : depuis le runtime tokio,
+// elles passent par `History::run`
+pub struct History {
+    connection: Mutex<Connection>,
+}
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS hdv_snapshots (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        taken_at INTEGER NOT NULL,
+        UNIQUE (server, taken_at)
+    );
+    CREATE TABLE IF NOT EXISTS hdv_listings (
+        snapshot_id INTEGER NOT NULL REFERENCES hdv_snapshots (id) ON DELETE CASCADE,
+        item TEXT NOT NULL,
+        price REAL NOT NULL,
+        quantity INTEGER NOT NULL,
+        seller TEXT
+    );
+    CREATE INDEX IF NOT EXISTS hdv_listings_item ON hdv_listings (item, snapshot_id);
+    CREATE INDEX IF NOT EXISTS hdv_listings_snapshot ON hdv_listings (snapshot_id);
+    CREATE TABLE IF NOT EXISTS player_counts (
+        server TEXT NOT NULL,
+        sampled_at INTEGER NOT NULL,
+        players INTEGER NOT NULL,
+        PRIMARY KEY (server, sampled_at)
+    );
+";
+
+// Prix d'un objet dans un relevé de l'HDV
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PricePoint {
+    pub time: DateTime<Utc>,
+    pub listings: u64,
+    pub quantity: u64,
+    pub min_price: f64,
+    pub avg_price: f64,
+    pub max_price: f64,
+}
+
+// Statistiques des prix d'un objet sur une période (None sans aucune annonce)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PriceStats {
+    pub listings: u64,
+    pub min: Option<f64>,
+    pub median: Option<f64>,
+    pub max: Option<f64>,
+}
+
+// Nombre d'annonces dans un relevé de l'HDV
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VolumePoint {
+    pub time: DateTime<Utc>,
+    pub listings: u64,
+    pub quantity: u64,
+}
+
+// Nombre de joueurs sur une période : pic, moyenne et nombre de relevés
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlayerCountStats {
+    pub peak: u64,
+    pub avg: f64,
+    pub samples: u64,
+}
+
+impl PlayerCountStats {
+    fn of(players: &[u64]) -> Self {
+        Self {
+            peak: players.iter().copied().max().unwrap_or_default(),
+            avg: players.iter().sum::<u64>() as f64 / players.len().max(1) as f64,
+            samples: players.len() as u64,
+        }
+    }
+}
+
+// Résolution de la série renvoyée par /playercount/history
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Raw,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    // "raw", "hour" ou "day" ("hour" par défaut)
+    pub fn parse(value: Option<&str>) -> Result<Self, ProxyError> {
+        match value.map(str::to_lowercase).as_deref() {
+            None | Some("hour") => Ok(Resolution::Hour),
+            Some("raw") => Ok(Resolution::Raw),
+            Some("day") => Ok(Resolution::Day),
+            Some(_) => Err(ProxyError::InvalidParameter {
+                name: "resolution".to_string(),
+            }),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Hour => "hour",
+            Resolution::Day => "day",
+        }
+    }
+
+    // Début de la période de la série contenant `time`
+    fn bucket(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let timestamp = time.timestamp();
+        match self {
+            Resolution::Raw => time,
+            Resolution::Hour => from_timestamp(timestamp - timestamp.rem_euclid(3600)),
+            Resolution::Day => from_timestamp(timestamp - timestamp.rem_euclid(86400)),
+        }
+    }
+}
+
+// Regroupe des relevés par clé (période, jour, heure de la semaine...)
+fn group_player_counts<K: Ord>(
+    samples: &[(DateTime<Utc>, u64)],
+    key: impl Fn(DateTime<Utc>) -> K,
+) -> Vec<(K, PlayerCountStats)> {
+    let mut groups: BTreeMap<K, Vec<u64>> = BTreeMap::new();
+    for (time, players) in samples {
+        groups.entry(key(*time)).or_default().push(*players);
+    }
+    groups
+        .into_iter()
+        .map(|(key, players)| (key, PlayerCountStats::of(&players)))
+        .collect()
+}
+
+// Série du nombre de joueurs à la résolution demandée
+pub fn player_count_series(
+    samples: &[(DateTime<Utc>, u64)],
+    resolution: Resolution,
+) -> Vec<(DateTime<Utc>, PlayerCountStats)> {
+    group_player_counts(samples, |time| resolution.bucket(time))
+}
+
+// Pic et moyenne par jour (UTC)
+pub fn player_counts_by_day(samples: &[(DateTime<Utc>, u64)]) -> Vec<(NaiveDate, PlayerCountStats)> {
+    group_player_counts(samples, |time| time.date_naive())
+}
+
+// Pic et moyenne par heure de la semaine (UTC) : (jour de la semaine, heure)
+pub fn player_counts_by_hour_of_week(samples: &[(DateTime<Utc>, u64)]) -> Vec<((Weekday, u32), PlayerCountStats)> {
+    // Regroupés par numéro du jour (lundi = 0) pour que la semaine commence le lundi
+    group_player_counts(samples, |time| (time.weekday().num_days_from_monday() as u8, time.hour()))
+        .into_iter()
+        .map(|((weekday, hour), stats)| ((Weekday::try_from(weekday).unwrap_or(Weekday::Mon), hour), stats))
+        .collect()
+}
+
+// Annonce normalisée : nom de l'objet en minuscules, quantité de 1 si l'API ne l'indique pas
+#[derive(Debug, Clone, PartialEq)]
+pub struct NormalizedListing {
+    pub item: String,
+    pub price: f64,
+    pub quantity: u64,
+    pub seller: Option<String>,
+}
+
+impl NormalizedListing {
+    // Les annonces sans objet ou sans prix sont ignorées
+    pub fn from_listing(listing: HdvListing) -> Option<Self> {
+        let item = listing.item?.trim().to_lowercase();
+        let price = listing.price.filter(|price| price.is_finite())?;
+        if item.is_empty() {
+            return None;
+        }
+        Some(Self {
+            item,
+            price,
+            quantity: listing.quantity.unwrap_or(1),
+            seller: listing.seller,
+        })
+    }
+}
+
+impl History {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    // Base en mémoire, perdue à l'arrêt (utilisée si le fichier ne peut pas être ouvert)
+    pub fn in_memory() -> Self {
+        Self::with_connection(Connection::open_in_memory().expect("in-memory SQLite database"))
+            .expect("valid history schema")
+    }
+
+    fn with_connection(connection: Connection) -> rusqlite::Result<Self> {
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // Exécute une opération sur la base dans un thread dédié aux tâches bloquantes, sans bloquer le runtime tokio
+    pub async fn run<T, F>(self: &Arc<Self>, operation: F) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&History) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let history = self.clone();
+        match tokio::task::spawn_blocking(move || operation(&history)).await {
+            Ok(result) => result,
+            Err(error) => match error.try_into_panic() {
+                Ok(panic) => std::panic::resume_unwind(panic),
+                // Tâche annulée : le runtime est en train de s'arrêter
+                Err(_) => Err(rusqlite::Error::SqliteFailure(
+                    rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_INTERRUPT),
+                    Some("history operation cancelled".to_string()),
+                )),
+            },
+        }
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Enregistre un relevé de l'HDV d'un serveur. Renvoie false s'il était déjà enregistré
+    // (même serveur, même date : la réponse venait du cache)
+    pub fn record_hdv(
+        &self,
+        server: Server,
+        taken_at: DateTime<Utc>,
+        listings: &[NormalizedListing],
+    ) -> rusqlite::Result<bool> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let inserted = transaction.execute(
+            "INSERT OR IGNORE INTO hdv_snapshots (server, taken_at) VALUES (?1, ?2)",
+            params![server.as_str(), taken_at.timestamp()],
+        )?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        let snapshot_id = transaction.last_insert_rowid();
+        {
+            let mut insert = transaction.prepare(
+                "INSERT INTO hdv_listings (snapshot_id, item, price, quantity, seller) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for listing in listings {
+                insert.execute(params![
+                    snapshot_id,
+                    listing.item,
+                    listing.price,
+                    listing.quantity as i64,
+                    listing.seller
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    // Prix d'un objet dans chaque relevé de la période
+    pub fn hdv_price_history(
+        &self,
+        server: Server,
+        item: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> rusqlite::Result<Vec<PricePoint>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT s.taken_at, COUNT(*), SUM(l.quantity), MIN(l.price), AVG(l.price), MAX(l.price)
+             FROM hdv_snapshots s JOIN hdv_listings l ON l.snapshot_id = s.id
+             WHERE s.server = ?1 AND l.item = ?2 AND s.taken_at BETWEEN ?3 AND ?4
+             GROUP BY s.id ORDER BY s.taken_at",
+        )?;
+        let points = statement.query_map(
+            params![server.as_str(), normalize_item(item), from.timestamp(), to.timestamp()],
+            |row| {
+                Ok(PricePoint {
+                    time: from_timestamp(row.get(0)?),
+                    listings: row.get::<_, i64>(1)? as u64,
+                    quantity: row.get::<_, i64>(2)? as u64,
+                    min_price: row.get(3)?,
+                    avg_price: row.get(4)?,
+                    max_price: row.get(5)?,
+                })
+            },
+        )?;
+        points.collect()
+    }
+
+    // Prix minimum, médian et maximum d'un objet sur la période
+    pub fn hdv_price_stats(
+        &self,
+        server: Server,
+        item: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> rusqlite::Result<PriceStats> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT l.price FROM hdv_snapshots s JOIN hdv_listings l ON l.snapshot_id = s.id
+             WHERE s.server = ?1 AND l.item = ?2 AND s.taken_at BETWEEN ?3 AND ?4
+             ORDER BY l.price",
+        )?;
+        let prices = statement
+            .query_map(
+                params![server.as_str(), normalize_item(item), from.timestamp(), to.timestamp()],
+                |row| row.get::<_, f64>(0),
+            )?
+            .collect::<rusqlite::Result<Vec<f64>>>()?;
+        Ok(PriceStats {
+            listings: prices.len() as u64,
+            min: prices.first().copied(),
+            median: median(&prices),
+            max: prices.last().copied(),
+        })
+    }
+
+    // Nombre d'annonces (d'un objet, ou de tous les objets) dans chaque relevé de la période
+    pub fn hdv_volume(
+        &self,
+        server: Server,
+        item: Option<&str>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> rusqlite::Result<Vec<VolumePoint>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT s.taken_at, COUNT(l.item), COALESCE(SUM(l.quantity), 0)
+             FROM hdv_snapshots s LEFT JOIN hdv_listings l ON l.snapshot_id = s.id AND (?2 IS NULL OR l.item = ?2)
+             WHERE s.server = ?1 AND s.taken_at BETWEEN ?3 AND ?4
+             GROUP BY s.id ORDER BY s.taken_at",
+        )?;
+        let points = statement.query_map(
+            params![server.as_str(), item.map(normalize_item), from.timestamp(), to.timestamp()],
+            |row| {
+                Ok(VolumePoint {
+                    time: from_timestamp(row.get(0)?),
+                    listings: row.get::<_, i64>(1)? as u64,
+                    quantity: row.get::<_, i64>(2)? as u64,
+                })
+            },
+        )?;
+        points.collect()
+    }
+
+    // Supprime les relevés (HDV et nombre de joueurs) antérieurs à `before`. Renvoie le nombre de relevés supprimés
+    pub fn prune(&self, before: DateTime<Utc>) -> rusqlite::Result<usize> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        // Les annonces des relevés supprimés le sont aussi (ON DELETE CASCADE)
+        let snapshots = transaction.execute("DELETE FROM hdv_snapshots WHERE taken_at < ?1", params![before.timestamp()])?;
+        let samples = transaction.execute("DELETE FROM player_counts WHERE sampled_at < ?1", params![before.timestamp()])?;
+        transaction.commit()?;
+        Ok(snapshots + samples)
+    }
+
+    // Enregistre le nombre de joueurs de chaque serveur à un instant donné. Renvoie le nombre de relevés ajoutés
+    pub fn record_player_counts(&self, sampled_at: DateTime<Utc>, counts: &[(Server, u64)]) -> rusqlite::Result<usize> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut insert = transaction.prepare(
+                "INSERT OR IGNORE INTO player_counts (server, sampled_at, players) VALUES (?1, ?2, ?3)",
+            )?;
+            for (server, players) in counts {
+                inserted += insert.execute(params![server.as_str(), sampled_at.timestamp(), *players as i64])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(inserted)
+    }
+
+    // Relevés du nombre de joueurs d'un serveur sur la période, dans l'ordre chronologique
+    pub fn player_counts(
+        &self,
+        server: Server,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> rusqlite::Result<Vec<(DateTime<Utc>, u64)>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT sampled_at, players FROM player_counts
+             WHERE server = ?1 AND sampled_at BETWEEN ?2 AND ?3 ORDER BY sampled_at",
+        )?;
+        let samples = statement.query_map(
+            params![server.as_str(), from.timestamp(), to.timestamp()],
+            |row| Ok((from_timestamp(row.get(0)?), row.get::<_, i64>(1)? as u64)),
+        )?;
+        samples.collect()
+    }
+}
+
+fn normalize_item(item: &str) -> String {
+    item.trim().to_lowercase()
+}
+
+fn from_timestamp(timestamp: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(timestamp, 0).unwrap_or_default()
+}
+
+// Médiane d'une liste de valeurs triées
+pub fn median(sorted: &[f64]) -> Option<f64> {
+    let middle = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((sorted[middle - 1] + sorted[middle]) / 2.0),
+        _ => Some(sorted[middle]),
+    }
+}
+
+// Date passée en paramètre d'une requête : RFC 3339 ("2024-06-01T12:00:00Z") ou jour ("2024-06-01", à minuit UTC)
+pub fn parse_time(name: &str, value: Option<&str>) -> Result<Option<DateTime<Utc>>, ProxyError> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(Some(time.with_timezone(&Utc)));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| Some(time.and_utc()))
+        .ok_or_else(|| ProxyError::InvalidParameter {
+            name: name.to_string(),
+        })
+}
+
+// Date du relevé contenu dans une réponse : celle de l'appel à l'API mis en cache (identique d'une lecture du cache
+// à l'autre, pour que le même relevé ne soit pas enregistré deux fois), sinon maintenant
+fn snapshot_time(response: &ProxyResponse) -> DateTime<Utc> {
+    response.cached_time.unwrap_or_else(Utc::now)
+}
+
+// Relève l'HDV de chaque serveur et l'enregistre dans l'historique
+pub async fn collect_hdv(collector: Collector, history: &Arc<History>) {
+    for server in Server::ALL {
+        let response = match collector.fetch("hdv", &format!("/hdv/{}/list", server)).await {
+            Ok(response) if (200..300).contains(&response.status) => response,
+            Ok(response) => {
+                tracing::warn!(%server, status = response.status, "HDV snapshot failed");
+                continue;
+            }
+            Err(error) => {
+                tracing::warn!(%server, code = %error.code(), "HDV snapshot failed");
+                continue;
+            }
+        };
+        // Chaque annonce est lue séparément : une annonce inattendue n'empêche pas d'enregistrer les autres
+        let listings: Vec<NormalizedListing> = response
+            .body
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|listing| HdvListing::deserialize(listing).ok())
+            .filter_map(NormalizedListing::from_listing)
+            .collect();
+        let count = listings.len();
+        let taken_at = snapshot_time(&response);
+        match history.run(move |history| history.record_hdv(server, taken_at, &listings)).await {
+            Ok(true) => tracing::info!(%server, listings = count, "HDV snapshot recorded"),
+            Ok(false) => tracing::debug!(%server, "HDV snapshot already recorded"),
+            Err(error) => tracing::warn!(%server, %error, "HDV snapshot could not be stored"),
+        }
+    }
+    prune_history(&collector, history).await;
+}
+
+// Relève le nombre de joueurs de chaque serveur et l'enregistre dans l'historique
+pub async fn collect_player_counts(collector: Collector, history: &Arc<History>) {
+    let response = match collector.fetch("playercount", "/playercount").await {
+        Ok(response) if (200..300).contains(&response.status) => response,
+        Ok(response) => {
+            tracing::warn!(status = response.status, "player count sample failed");
+            return;
+        }
+        Err(error) => {
+            tracing::warn!(code = %error.code(), "player count sample failed");
+            return;
+        }
+    };
+    let Ok(player_count) = PlayerCount::deserialize(&response.body) else {
+        tracing::warn!("unexpected player count response");
+        return;
+    };
+    // Seuls les serveurs connus du proxy sont enregistrés
+    let counts: Vec<(Server, u64)> = player_count
+        .servers
+        .into_iter()
+        .filter_map(|(name, players)| Some((Server::from_name(&name)?, players)))
+        .collect();
+    let sampled_at = snapshot_time(&response);
+    match history.run(move |history| history.record_player_counts(sampled_at, &counts)).await {
+        Ok(inserted) => tracing::debug!(inserted, "player counts recorded"),
+        Err(error) => tracing::warn!(%error, "player counts could not be stored"),
+    }
+    prune_history(&collector, history).await;
+}
+
+// Date avant laquelle les relevés sont supprimés, None si elle sort des dates représentables
+pub fn retention_cutoff(now: DateTime<Utc>, retention_days: u64) -> Option<DateTime<Utc>> {
+    let retention = chrono::Duration::try_days(i64::try_from(retention_days).ok()?)?;
+    now.checked_sub_signed(retention)
+}
+
+// Supprime les relevés plus anciens que `history_retention_days` (0 pour tout garder)
+async fn prune_history(collector: &Collector, history: &Arc<History>) {
+    let retention_days = collector.config.history_retention_days;
+    if retention_days == 0 {
+        return;
+    }
+    // Une durée de conservation plus longue que ce que chrono sait représenter : rien n'est assez vieux pour être supprimé
+    let Some(before) = retention_cutoff(Utc::now(), retention_days) else {
+        return;
+    };
+    match history.run(move |history| history.prune(before)).await {
+        Ok(0) => {}
+        Ok(pruned) => tracing::info!(pruned, "old history samples pruned"),
+        Err(error) => tracing::warn!(%error, "history could not be pruned"),
+    }
+}
@@ -0,0 +1,159 @@
+use crate::cache::{cache_from_config, CacheError, MemoryCache};
+use crate::collector::Collector;
+use crate::config::Config;
+use crate::endpoints::{
+    get_country, get_country_list, get_hdv, get_healthz, get_metrics, get_ngisland_list, get_notations,
+    get_planning, get_playercount, get_readyz, get_servers, get_user, get_hdv_history, get_hdv_stats,
+    get_hdv_volume, get_playercount_history, get_notations_range,
+};
+use crate::error::default_catcher;
+use crate::health::Health;
+use crate::history::{collect_hdv, collect_player_counts, History};
+use crate::logging::RequestIdFairing;
+use crate::metrics::Metrics;
+use crate::queue_limits::QueueLimits;
+use crate::rate_limit::ApiKeyUsage;
+use crate::shutdown::{Shutdown, ShutdownFairing};
+use crate::waiters::Waiters;
+use crate::worker::process_requests_v2;
+use rocket::fs::{relative, FileServer};
+use rocket::{catchers, routes, Build, Rocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+pub mod cache;
+pub mod collector;
+pub mod config;
+pub mod endpoints;
+pub mod error;
+pub mod health;
+pub mod history;
+pub mod logging;
+pub mod metrics;
+pub mod models;
+pub mod queue_limits;
+pub mod rankings;
+pub mod rate_limit;
+pub mod server;
+pub mod shutdown;
+pub mod utils;
+pub mod waiters;
+pub mod worker;
+
+// Construit l'application Rocket et lance le worker (doit être appelé depuis un runtime tokio)
+// Échoue si le cache choisi explicitement (cache_backend) ne peut pas être créé
+pub fn build_rocket(config: Config) -> Result<Rocket<Build>, CacheError> {
+    let (queue_tx, queue_rx) = mpsc::channel(config.queue_size);
+    let api_key_usage = Arc::new(ApiKeyUsage::from_config(&config));
+    let cache = match cache_from_config(&config) {
+        Ok(cache) => cache,
+        // Redis n'a été choisi que parce que redis_url est défini : on se rabat sur le cache en mémoire
+        Err(error) if config.cache_backend.is_none() => {
+            tracing::warn!(%error, "falling back to the in-memory cache");
+            Arc::new(MemoryCache::new(config.cache_memory_capacity))
+        }
+        Err(error) => return Err(error),
+    };
+    let waiters = Arc::new(Waiters::new());
+    let metrics = Arc::new(Metrics::new());
+    let health = Arc::new(Health::new());
+    let shutdown = Arc::new(Shutdown::new());
+    let queue_limits = Arc::new(QueueLimits::new(
+        config.max_queued_requests,
+        config.max_queued_per_client,
+    ));
+    let history = Arc::new(History::open(&config.history_path).unwrap_or_else(|error| {
+        tracing::warn!(%error, "falling back to an in-memory history");
+        History::in_memory()
+    }));
+
+    // Lancer la tâche de worker dans un contexte async
+    let worker_cache = cache.clone();
+    let worker_waiters = waiters.clone();
+    let worker_metrics = metrics.clone();
+    let worker_health = health.clone();
+    let worker_api_key_usage = api_key_usage.clone();
+    let worker_shutdown = shutdown.clone();
+    let worker_config = config.clone();
+    let worker = tokio::spawn(async move {
+        process_requests_v2(
+            queue_rx,
+            worker_api_key_usage,
+            worker_cache,
+            worker_waiters,
+            worker_metrics,
+            worker_health,
+            worker_shutdown,
+            worker_config,
+        )
+        .await;
+    });
+    // Collecteurs d'historiques, passant par la file d'attente comme les clients
+    let collector = Collector {
+        queue: queue_tx.clone(),
+        cache: cache.clone(),
+        waiters: waiters.clone(),
+        metrics: metrics.clone(),
+        shutdown: shutdown.clone(),
+        api_key_usage: api_key_usage.clone(),
+        queue_limits: queue_limits.clone(),
+        config: Arc::new(config.clone()),
+    };
+    let hdv_history = history.clone();
+    collector.spawn_every("hdv", config.hdv_history_interval, move |collector| {
+        let history = hdv_history.clone();
+        async move { collect_hdv(collector, &history).await }
+    });
+    let playercount_history = history.clone();
+    collector.spawn_every("playercount", config.playercount_history_interval, move |collector| {
+        let history = playercount_history.clone();
+        async move { collect_player_counts(collector, &history).await }
+    });
+
+    // Le worker limite lui-même les appels en cours à shutdown_timeout : on lui laisse une seconde de plus pour finir
+    let shutdown_fairing = ShutdownFairing {
+        shutdown: shutdown.clone(),
+        worker: Mutex::new(Some(worker)),
+        timeout: Duration::from_secs(config.shutdown_timeout + 1),
+    };
+
+    let rocket = rocket::build()
+        .manage(queue_tx)
+        .manage(cache)
+        .manage(waiters)
+        .manage(metrics)
+        .manage(health)
+        .manage(shutdown)
+        .manage(api_key_usage)
+        .manage(queue_limits)
+        .manage(history)
+        .manage(config)
+        .mount("/", FileServer::from(relative!("static"))) // Chargement des fichiers /static sur l'endpoint /
+        .mount(
+            "/",
+            routes![
+                get_planning,
+                get_playercount,
+                get_playercount_history,
+                get_servers,
+                get_hdv,
+                get_hdv_history,
+                get_hdv_stats,
+                get_hdv_volume,
+                get_notations,
+                get_notations_range,
+                get_country,
+                get_country_list,
+                get_user,
+                get_ngisland_list,
+                get_metrics,
+                get_healthz,
+                get_readyz
+            ],
+        )
+        .register("/", catchers![default_catcher])
+        .attach(RequestIdFairing)
+        .attach(shutdown_fairing);
+    Ok(rocket)
+}
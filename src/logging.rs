@@ -0,0 +1,78 @@
+use crate::config::Config;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Data, Request, Response};
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+// Longueur maximale d'un identifiant de requête fourni par le client
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+// Logs au format JSON, filtrés selon `log_level` (syntaxe de RUST_LOG, par exemple "info" ou "nationsglory_api_proxy=debug")
+pub fn init_logging(config: &Config) {
+    let filter = EnvFilter::try_new(&config.log_level).unwrap_or_else(|_| EnvFilter::new("info"));
+    // Un subscriber est peut-être déjà installé (tests) : on garde alors le premier
+    let _ = tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(filter)
+        .with_current_span(true)
+        .try_init();
+}
+
+// Identifiant de la requête, repris de l'en-tête X-Request-Id du client s'il est valide, sinon généré
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn from_header(value: Option<&str>) -> Self {
+        match value {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LENGTH
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                RequestId(id.to_string())
+            }
+            _ => RequestId(uuid::Uuid::new_v4().to_string()),
+        }
+    }
+
+    // Identifiant attribué à la requête par RequestIdFairing
+    pub fn of(req: &Request<'_>) -> String {
+        req.local_cache(|| RequestId::from_header(req.headers().get_one(REQUEST_ID_HEADER)))
+            .0
+            .clone()
+    }
+}
+
+// Attribue un identifiant à chaque requête et le renvoie dans l'en-tête X-Request-Id de la réponse
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ID",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let request_id = RequestId::of(req);
+        tracing::debug!(request_id = %request_id, method = %req.method(), uri = %req.uri(), "request received");
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let request_id = RequestId::of(req);
+        tracing::info!(
+            request_id = %request_id,
+            method = %req.method(),
+            uri = %req.uri(),
+            status = res.status().code,
+            "request completed"
+        );
+        res.set_header(Header::new(REQUEST_ID_HEADER, request_id));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::rate_limit::{key_id, ApiKeyUsage};
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+// Buckets (en secondes) de l'histogramme des temps de réponse de l'API
+const UPSTREAM_LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+// Métriques du proxy exposées au format Prometheus sur /metrics
+pub struct Metrics {
+    registry: Registry,
+    pub queue_depth: IntGauge,
+    pub keys_in_use: IntGauge,
+    keys_seen: IntGauge,
+    key_requests: IntCounterVec,
+    key_last_used: GaugeVec,
+    exported_keys: Mutex<HashSet<String>>, // Identifiants des clés API ayant actuellement des séries key_requests / key_last_used
+    cache_requests: IntCounterVec,
+    upstream_latency: HistogramVec,
+    upstream_responses: IntCounterVec,
+    pub coalesced_requests: IntCounter,
+    rejected_requests: IntCounterVec,
+    schema_mismatches: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Self {
+            queue_depth: IntGauge::new(
+                "proxy_queue_depth",
+                "Number of requests waiting for an API key in the worker",
+            )
+            .expect("valid metric"),
+            keys_in_use: IntGauge::new(
+                "proxy_api_keys_in_use",
+                "Number of API keys currently used by an upstream call",
+            )
+            .expect("valid metric"),
+            keys_seen: IntGauge::new(
+                "proxy_api_keys_seen",
+                "Number of distinct API keys used recently",
+            )
+            .expect("valid metric"),
+            key_requests: IntCounterVec::new(
+                Opts::new(
+                    "proxy_api_key_requests_total",
+                    "Upstream calls made with each API key (identified by a short hash)",
+                ),
+                &["key"],
+            )
+            .expect("valid metric"),
+            key_last_used: GaugeVec::new(
+                Opts::new(
+                    "proxy_api_key_last_used_timestamp_seconds",
+                    "Last time each API key (identified by a short hash) was used",
+                ),
+                &["key"],
+            )
+            .expect("valid metric"),
+            exported_keys: Mutex::new(HashSet::new()),
+            cache_requests: IntCounterVec::new(
+                Opts::new(
+                    "proxy_cache_requests_total",
+                    "Cache lookups by outcome (hit, miss, stale)",
+                ),
+                &["outcome"],
+            )
+            .expect("valid metric"),
+            upstream_latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "proxy_upstream_request_duration_seconds",
+                    "Duration of the calls to the NationsGlory API by route",
+                )
+                .buckets(UPSTREAM_LATENCY_BUCKETS.to_vec()),
+                &["route"],
+            )
+            .expect("valid metric"),
+            upstream_responses: IntCounterVec::new(
+                Opts::new(
+                    "proxy_upstream_responses_total",
+                    "Responses of the NationsGlory API by route and status (or error)",
+                ),
+                &["route", "status"],
+            )
+            .expect("valid metric"),
+            coalesced_requests: IntCounter::new(
+                "proxy_coalesced_requests_total",
+                "Requests merged with an identical request already waiting in the queue",
+            )
+            .expect("valid metric"),
+            rejected_requests: IntCounterVec::new(
+                Opts::new(
+                    "proxy_rejected_requests_total",
+                    "Requests answered with 429 because the queue limit (total or per client) was reached",
+                ),
+                &["limit"],
+            )
+            .expect("valid metric"),
+            schema_mismatches: IntCounterVec::new(
+                Opts::new(
+                    "proxy_upstream_schema_mismatches_total",
+                    "Upstream responses that do not match the expected schema, by route",
+                ),
+                &["route"],
+            )
+            .expect("valid metric"),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 11] = [
+            Box::new(metrics.queue_depth.clone()),
+            Box::new(metrics.keys_in_use.clone()),
+            Box::new(metrics.keys_seen.clone()),
+            Box::new(metrics.key_requests.clone()),
+            Box::new(metrics.key_last_used.clone()),
+            Box::new(metrics.cache_requests.clone()),
+            Box::new(metrics.upstream_latency.clone()),
+            Box::new(metrics.upstream_responses.clone()),
+            Box::new(metrics.coalesced_requests.clone()),
+            Box::new(metrics.rejected_requests.clone()),
+            Box::new(metrics.schema_mismatches.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric registered once");
+        }
+        metrics
+    }
+
+    // Résultat de la recherche d'une réponse dans le cache : "hit", "miss" ou "stale"
+    pub fn cache_outcome(&self, outcome: &str) {
+        self.cache_requests.with_label_values(&[outcome]).inc();
+    }
+
+    // Requête refusée car la file d'attente est pleine : `limit` vaut "total" ou "client"
+    pub fn rejected_request(&self, limit: &str) {
+        self.rejected_requests.with_label_values(&[limit]).inc();
+    }
+
+    // Réponse de l'API ne correspondant pas au modèle attendu pour la route
+    pub fn schema_mismatch(&self, route: &str) {
+        self.schema_mismatches.with_label_values(&[route]).inc();
+    }
+
+    // Appel à l'API terminé : `status` est le code HTTP, ou "error" / "timeout" si l'API n'a pas répondu
+    pub fn upstream_response(&self, route: &str, status: &str, duration: Duration) {
+        self.upstream_latency
+            .with_label_values(&[route])
+            .observe(duration.as_secs_f64());
+        self.upstream_responses
+            .with_label_values(&[route, status])
+            .inc();
+    }
+
+    // Export au format texte de Prometheus (les statistiques des clés API sont relues à chaque export)
+    // Les clés refusées par l'API et celles oubliées par ApiKeyUsage perdent leurs séries
+    pub fn render(&self, api_key_usage: &ApiKeyUsage) -> String {
+        let stats = api_key_usage.stats();
+        self.keys_seen.set(stats.len() as i64);
+        let mut exported = HashSet::new();
+        for (api_key, stats) in stats {
+            if api_key_usage.is_invalid(&api_key) {
+                continue;
+            }
+            let key = key_id(&api_key);
+            let requests = self.key_requests.with_label_values(&[&key]);
+            requests.inc_by(stats.requests.saturating_sub(requests.get()));
+            let last_used = stats
+                .last_used
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            self.key_last_used
+                .with_label_values(&[&key])
+                .set(last_used.as_secs_f64());
+            exported.insert(key);
+        }
+        let mut exported_keys = self.exported_keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for key in exported_keys.difference(&exported) {
+            let _ = self.key_requests.remove_label_values(&[key]);
+            let _ = self.key_last_used.remove_label_values(&[key]);
+        }
+        *exported_keys = exported;
+        drop(exported_keys);
+
+        let mut buffer = Vec::new();
+        // L'encodage ne peut échouer que pour des métriques invalides, qui sont toutes définies ci-dessus
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
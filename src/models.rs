@@ -0,0 +1,147 @@
+use crate::error::ProxyError;
+use crate::utils::{ProxyContext, ProxyResponse, CACHE_STATUS_HEADER};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+// Modèles des réponses de l'API NationsGlory
+// Tous les champs sont facultatifs et les champs inconnus sont conservés dans `extra` : un champ ajouté par l'API
+// ne casse rien, seul un champ du mauvais type (ou une réponse de forme inattendue) est signalé comme une dérive du schéma
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Country {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+// Entrée de /country/list : le nom du pays seul, ou un objet décrivant le pays
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CountryListEntry {
+    Name(String),
+    Country(Country),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Notation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pays: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notation: Option<f64>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Notation {
+    // Le pays noté correspond-il à `country` (sans tenir compte de la casse) ?
+    pub fn is_country(&self, country: &str) -> bool {
+        self.pays
+            .as_deref()
+            .is_some_and(|pays| pays.to_lowercase() == country.to_lowercase())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Skill {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<f64>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct User {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skills: Vec<Skill>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HdvListing {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub item: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quantity: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seller: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlanningEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+// Nombre de joueurs connectés sur chaque serveur
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PlayerCount {
+    pub servers: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NgIsland {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+// Page de /ngisland/list
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NgIslandPage {
+    pub data: Vec<NgIsland>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<u64>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+// Vérifie qu'une réponse fraîche de l'API correspond au modèle attendu pour la route
+// Une dérive du schéma est comptée dans les métriques et journalisée, mais la réponse est renvoyée telle quelle
+pub fn validate<T: DeserializeOwned>(
+    proxy: &ProxyContext<'_>,
+    route: &str,
+    response: &Result<ProxyResponse, ProxyError>,
+) {
+    let Ok(response) = response else {
+        return;
+    };
+    // Les réponses déjà vérifiées (cache) et les erreurs de l'API ne sont pas concernées
+    let fresh = response.header(CACHE_STATUS_HEADER) == Some("MISS");
+    if !fresh || !(200..300).contains(&response.status) {
+        return;
+    }
+    if let Err(error) = T::deserialize(&response.body) {
+        proxy.metrics.schema_mismatch(route);
+        tracing::warn!(request_id = %proxy.request_id, route, %error, "upstream response does not match the expected schema");
+    }
+}
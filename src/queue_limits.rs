@@ -0,0 +1,102 @@
+use crate::error::ProxyError;
+use crate::rate_limit::ApiKeyUsage;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+// Nombre de requêtes en attente d'une réponse de l'API, au total et par client (jeu de clés API)
+// Au-delà des limites, le proxy répond lui-même 429 au lieu de laisser la file grossir indéfiniment
+#[derive(Debug, Default)]
+pub struct QueueLimits {
+    max_total: usize,      // 0 = pas de limite
+    max_per_client: usize, // 0 = pas de limite
+    total: AtomicUsize,
+    per_client: DashMap<String, usize>,
+}
+
+// Limite dépassée par une requête
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueLimit {
+    Total,
+    Client,
+}
+
+impl QueueLimit {
+    // Libellé utilisé dans les métriques
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueLimit::Total => "total",
+            QueueLimit::Client => "client",
+        }
+    }
+}
+
+impl QueueLimits {
+    pub fn new(max_total: usize, max_per_client: usize) -> Self {
+        Self {
+            max_total,
+            max_per_client,
+            ..Self::default()
+        }
+    }
+
+    // Réserve une place dans la file pour une requête du client, libérée lorsque le `QueueSlot` est détruit
+    pub fn try_acquire(self: &Arc<Self>, client: &str) -> Result<QueueSlot, QueueLimit> {
+        let mut count = self.per_client.entry(client.to_string()).or_default();
+        if self.max_per_client > 0 && *count >= self.max_per_client {
+            return Err(QueueLimit::Client);
+        }
+        let reserved = self
+            .total
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |total| {
+                (self.max_total == 0 || total < self.max_total).then_some(total + 1)
+            });
+        if reserved.is_err() {
+            // On ne garde pas de compteur vide pour un client qui n'a aucune requête en attente
+            drop(count);
+            self.per_client.remove_if(client, |_, count| *count == 0);
+            return Err(QueueLimit::Total);
+        }
+        *count += 1;
+        Ok(QueueSlot {
+            limits: self.clone(),
+            client: client.to_string(),
+        })
+    }
+
+    fn release(&self, client: &str) {
+        self.total.fetch_sub(1, Ordering::AcqRel);
+        self.per_client.remove_if_mut(client, |_, count| {
+            *count = count.saturating_sub(1);
+            *count == 0
+        });
+    }
+}
+
+// Place réservée dans la file d'attente
+#[derive(Debug)]
+pub struct QueueSlot {
+    limits: Arc<QueueLimits>,
+    client: String,
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.limits.release(&self.client);
+    }
+}
+
+// Erreur renvoyée lorsque la file est pleine, avec le délai (en secondes) au bout duquel une des clés API
+// du client devrait de nouveau être utilisable
+pub fn queue_full(api_key_usage: &ApiKeyUsage, api_keys: &[String]) -> ProxyError {
+    let now = Instant::now();
+    let retry_after = api_keys
+        .iter()
+        .map(|api_key| api_key_usage.next_available(api_key).saturating_duration_since(now))
+        .min()
+        .unwrap_or_default();
+    ProxyError::QueueFull {
+        retry_after: retry_after.as_secs_f64().ceil().max(1.0) as u64,
+    }
+}
@@ -0,0 +1,82 @@
+use crate::models::Notation;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// Note et rang d'un pays pour une semaine
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WeeklyRank {
+    pub week: i64,
+    pub notation: f64,
+    pub rank: usize,
+}
+
+// Classement d'un pays sur plusieurs semaines
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CountryRanking {
+    pub country: String,
+    pub rank: usize, // rang selon le total sur la période
+    pub total: f64,
+    pub average: f64, // moyenne des semaines où le pays est noté
+    pub weeks: Vec<WeeklyRank>,
+    pub rank_movement: i64, // premier rang - dernier rang : positif si le pays a progressé
+}
+
+// Classe les pays à partir des notations de chaque semaine
+// Les notations d'un même pays (sans tenir compte de la casse) sont additionnées ; les ex æquo partagent le même rang
+pub fn rank_notations(weeks: &[(i64, Vec<Notation>)]) -> Vec<CountryRanking> {
+    let mut names: BTreeMap<String, String> = BTreeMap::new();
+    let mut weekly: BTreeMap<String, Vec<WeeklyRank>> = BTreeMap::new();
+    for (week, notations) in weeks {
+        let mut scores: BTreeMap<String, f64> = BTreeMap::new();
+        for notation in notations {
+            let (Some(country), Some(value)) = (&notation.pays, notation.notation) else {
+                continue;
+            };
+            let key = country.to_lowercase();
+            names.entry(key.clone()).or_insert_with(|| country.clone());
+            *scores.entry(key).or_default() += value;
+        }
+        for (country, rank) in ranks(&scores) {
+            weekly.entry(country.clone()).or_default().push(WeeklyRank {
+                week: *week,
+                notation: scores[&country],
+                rank,
+            });
+        }
+    }
+
+    let totals: BTreeMap<String, f64> = weekly
+        .iter()
+        .map(|(country, weeks)| (country.clone(), weeks.iter().map(|week| week.notation).sum()))
+        .collect();
+    let mut rankings: Vec<CountryRanking> = ranks(&totals)
+        .into_iter()
+        .map(|(country, rank)| {
+            let mut weeks = weekly.remove(&country).unwrap_or_default();
+            weeks.sort_by_key(|week| week.week);
+            let first = weeks.first().map_or(0, |week| week.rank as i64);
+            let last = weeks.last().map_or(0, |week| week.rank as i64);
+            CountryRanking {
+                country: names.remove(&country).unwrap_or(country.clone()),
+                rank,
+                total: totals[&country],
+                average: totals[&country] / weeks.len().max(1) as f64,
+                weeks,
+                rank_movement: first - last,
+            }
+        })
+        .collect();
+    rankings.sort_by(|a, b| a.rank.cmp(&b.rank).then_with(|| a.country.cmp(&b.country)));
+    rankings
+}
+
+// Rang de chaque pays (1 pour la meilleure note) : le rang est 1 + le nombre de pays ayant une meilleure note
+fn ranks(scores: &BTreeMap<String, f64>) -> Vec<(String, usize)> {
+    scores
+        .iter()
+        .map(|(country, score)| {
+            let better = scores.values().filter(|other| *other > score).count();
+            (country.clone(), better + 1)
+        })
+        .collect()
+}
@@ -0,0 +1,365 @@
+use crate::config::Config;
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::time::{Duration, Instant, SystemTime};
+
+// Durée pendant laquelle une clé API refusée par l'API (401/403) est mise de côté
+const INVALID_KEY_DURATION: Duration = Duration::from_secs(60 * 10);
+// Durée de mise en pause d'une clé API ayant reçu une 429 sans en-tête Retry-After
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+// Délai maximal accepté dans les en-têtes de l'API : une valeur aberrante ne met pas la clé en pause indéfiniment
+const MAX_RATE_LIMIT_DELAY: Duration = Duration::from_secs(60 * 60 * 24);
+// Durée sans utilisation au-delà de laquelle une clé API est oubliée (statistiques, seau à jetons et métriques)
+const IDLE_KEY_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+
+// Informations de quota renvoyées par l'API dans les en-têtes de la réponse
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitHeaders {
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+    pub reset_after: Option<Duration>,
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimitHeaders {
+    pub fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        let get_u64 = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+
+        Self {
+            limit: get_u64("x-ratelimit-limit"),
+            remaining: get_u64("x-ratelimit-remaining"),
+            reset_after: get_u64("x-ratelimit-reset").map(parse_reset),
+            retry_after: headers
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after),
+        }
+    }
+}
+
+// X-RateLimit-Reset peut être soit un nombre de secondes, soit un timestamp UNIX
+pub fn parse_reset(value: u64) -> Duration {
+    let now = chrono::Utc::now().timestamp() as u64;
+    let delay = if value > now / 2 {
+        Duration::from_secs(value.saturating_sub(now))
+    } else {
+        Duration::from_secs(value)
+    };
+    delay.min(MAX_RATE_LIMIT_DELAY)
+}
+
+// Retry-After peut être soit un nombre de secondes, soit une date HTTP
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds).min(MAX_RATE_LIMIT_DELAY));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    let delay = date.with_timezone(&chrono::Utc) - chrono::Utc::now();
+    Some(delay.to_std().unwrap_or(Duration::ZERO).min(MAX_RATE_LIMIT_DELAY))
+}
+
+// Profil de limitation d'une clé API : taille du seau et nombre de jetons regagnés par seconde
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitProfile {
+    pub capacity: f64,
+    pub refill_per_second: f64,
+}
+
+impl Default for RateLimitProfile {
+    // Une requête toutes les 500ms, sans rafale (comportement historique du proxy)
+    fn default() -> Self {
+        Self {
+            capacity: 1.0,
+            refill_per_second: 2.0,
+        }
+    }
+}
+
+impl RateLimitProfile {
+    // Format: "<capacité>/<jetons par seconde>", par exemple "10/2"
+    pub fn parse(value: &str) -> Option<Self> {
+        let (capacity, refill_per_second) = value.split_once('/')?;
+        let profile = Self {
+            capacity: capacity.trim().parse().ok()?,
+            refill_per_second: refill_per_second.trim().parse().ok()?,
+        };
+        (profile.capacity >= 1.0 && profile.refill_per_second > 0.0).then_some(profile)
+    }
+}
+
+// Seau à jetons d'une clé API
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    profile: RateLimitProfile,
+    tokens: f64,
+    updated_at: Instant,
+    window: Option<Duration>, // Plus longue fenêtre de quota observée dans les en-têtes de l'API
+}
+
+impl TokenBucket {
+    fn new(profile: RateLimitProfile) -> Self {
+        Self {
+            profile,
+            tokens: profile.capacity,
+            updated_at: Instant::now(),
+            window: None,
+        }
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        (self.tokens + elapsed * self.profile.refill_per_second).min(self.profile.capacity)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.tokens = self.tokens_at(now);
+        self.updated_at = now;
+    }
+
+    // Date à laquelle le seau contiendra de nouveau au moins un jeton
+    fn next_token_at(&self, now: Instant) -> Instant {
+        let missing = 1.0 - self.tokens_at(now);
+        if missing <= 0.0 {
+            return now;
+        }
+        now + Duration::from_secs_f64(missing / self.profile.refill_per_second)
+    }
+}
+
+// Date de fin d'une mise en pause, le délai étant borné par MAX_RATE_LIMIT_DELAY
+fn blocked_until(now: Instant, delay: Duration) -> Instant {
+    now + delay.min(MAX_RATE_LIMIT_DELAY)
+}
+
+// Identifiant court d'une clé API, utilisable dans les métriques et les logs sans exposer la clé elle-même
+pub fn key_id(api_key: &str) -> String {
+    let digest = Sha256::digest(api_key.as_bytes());
+    digest[..4].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Identifiant court d'un client, c'est-à-dire du jeu de clés API qu'il envoie (quel que soit leur ordre)
+pub fn client_id(api_keys: &[String]) -> String {
+    let mut api_keys: Vec<&str> = api_keys.iter().map(String::as_str).collect();
+    api_keys.sort_unstable();
+    api_keys.dedup();
+    key_id(&api_keys.join(","))
+}
+
+// Statistiques d'utilisation d'une clé API
+#[derive(Debug, Clone, Copy)]
+pub struct KeyStats {
+    pub requests: u64,
+    pub last_used: SystemTime,
+}
+
+pub struct ApiKeyUsage {
+    default_profile: RateLimitProfile,
+    profiles: DashMap<String, RateLimitProfile>, // Profils configurés manuellement pour certaines clés API
+    buckets: DashMap<String, TokenBucket>, // Associe une clé API à son seau à jetons
+    invalid_keys: DashMap<String, Instant>, // Associe une clé API refusée par l'API à la date du refus
+    blocked_until: DashMap<String, Instant>, // Associe une clé API ayant reçu une 429 à la date où elle redevient utilisable
+    stats: DashMap<String, KeyStats>, // Associe une clé API au nombre de requêtes envoyées avec elle et à sa dernière utilisation
+}
+
+impl ApiKeyUsage {
+    pub fn new(default_profile: RateLimitProfile) -> Self {
+        Self {
+            default_profile,
+            profiles: DashMap::new(),
+            buckets: DashMap::new(),
+            invalid_keys: DashMap::new(),
+            blocked_until: DashMap::new(),
+            stats: DashMap::new(),
+        }
+    }
+
+    // Charge le profil par défaut (api_key_rate_limit) et les profils par clé (api_key_profiles) depuis la configuration
+    // Les profils par clé peuvent aussi être donnés par l'environnement : API_KEY_PROFILES="<clé>=10/2;<clé>=1/2"
+    pub fn from_config(config: &Config) -> Self {
+        let default_profile = config
+            .api_key_rate_limit
+            .as_deref()
+            .and_then(RateLimitProfile::parse)
+            .unwrap_or_default();
+        let usage = Self::new(default_profile);
+
+        for (api_key, profile) in &config.api_key_profiles {
+            if let Some(profile) = RateLimitProfile::parse(profile) {
+                usage.set_profile(api_key.clone(), profile);
+            }
+        }
+        if let Ok(profiles) = env::var("API_KEY_PROFILES") {
+            for entry in profiles.split(';') {
+                if let Some((api_key, profile)) = entry.split_once('=') {
+                    if let Some(profile) = RateLimitProfile::parse(profile) {
+                        usage.set_profile(api_key.trim().to_string(), profile);
+                    }
+                }
+            }
+        }
+        usage
+    }
+
+    pub fn set_profile(&self, api_key: String, profile: RateLimitProfile) {
+        if let Some(mut bucket) = self.buckets.get_mut(&api_key) {
+            bucket.profile = profile;
+        }
+        self.profiles.insert(api_key, profile);
+    }
+
+    fn profile_for(&self, api_key: &str) -> RateLimitProfile {
+        self.profiles
+            .get(api_key)
+            .map(|profile| *profile)
+            .unwrap_or(self.default_profile)
+    }
+
+    fn is_blocked(&self, api_key: &str, now: Instant) -> bool {
+        self.blocked_until
+            .get(api_key)
+            .is_some_and(|blocked_until| *blocked_until > now)
+    }
+
+    // Nombre de jetons actuellement disponibles pour la clé API
+    pub fn available_budget(&self, api_key: &String) -> f64 {
+        let now = Instant::now();
+        if self.is_blocked(api_key, now) {
+            return 0.0;
+        }
+        match self.buckets.get(api_key) {
+            Some(bucket) => bucket.tokens_at(now),
+            None => self.profile_for(api_key).capacity,
+        }
+    }
+
+    // Date à partir de laquelle la clé API peut de nouveau être utilisée
+    pub fn next_available(&self, api_key: &String) -> Instant {
+        let now = Instant::now();
+        let mut next = match self.buckets.get(api_key) {
+            Some(bucket) => bucket.next_token_at(now),
+            None => now,
+        };
+        if let Some(blocked_until) = self.blocked_until.get(api_key) {
+            next = next.max(*blocked_until);
+        }
+        next
+    }
+
+    // Consomme un jeton de la clé API
+    pub fn update_usage(&self, api_key: String) {
+        let now = Instant::now();
+        let profile = self.profile_for(&api_key);
+        self.stats
+            .entry(api_key.clone())
+            .and_modify(|stats| {
+                stats.requests += 1;
+                stats.last_used = SystemTime::now();
+            })
+            .or_insert(KeyStats {
+                requests: 1,
+                last_used: SystemTime::now(),
+            });
+        let mut bucket = self
+            .buckets
+            .entry(api_key)
+            .or_insert_with(|| TokenBucket::new(profile));
+        bucket.refill(now);
+        bucket.tokens -= 1.0;
+    }
+
+    // Met à jour le profil et le solde de la clé API à partir des en-têtes renvoyés par l'API
+    pub fn update_budget(&self, api_key: &str, headers: &RateLimitHeaders) {
+        let now = Instant::now();
+        let profile = self.profile_for(api_key);
+        let mut bucket = self
+            .buckets
+            .entry(api_key.to_string())
+            .or_insert_with(|| TokenBucket::new(profile));
+        bucket.refill(now);
+
+        if let Some(reset_after) = headers.reset_after {
+            let window = bucket.window.map_or(reset_after, |window| window.max(reset_after));
+            bucket.window = Some(window);
+
+            // Le profil n'est appris que pour les clés qui n'ont pas été configurées manuellement
+            if let Some(limit) = headers.limit.filter(|limit| *limit > 0) {
+                if !self.profiles.contains_key(api_key) && !window.is_zero() {
+                    bucket.profile = RateLimitProfile {
+                        capacity: limit as f64,
+                        refill_per_second: limit as f64 / window.as_secs_f64(),
+                    };
+                }
+            }
+        }
+
+        if let Some(remaining) = headers.remaining {
+            // On se cale sur le solde annoncé par l'API s'il est plus bas que le nôtre
+            bucket.tokens = bucket.tokens.min(remaining as f64);
+            if remaining == 0 {
+                if let Some(reset_after) = headers.reset_after {
+                    drop(bucket);
+                    self.blocked_until
+                        .insert(api_key.to_string(), blocked_until(now, reset_after));
+                }
+            }
+        }
+    }
+
+    // Met la clé API en pause suite à une 429
+    pub fn block(&self, api_key: &str, headers: &RateLimitHeaders) {
+        let delay = headers
+            .retry_after
+            .or(headers.reset_after)
+            .unwrap_or(DEFAULT_RETRY_AFTER);
+        if let Some(mut bucket) = self.buckets.get_mut(api_key) {
+            bucket.tokens = bucket.tokens.min(0.0);
+        }
+        self.blocked_until
+            .insert(api_key.to_string(), blocked_until(Instant::now(), delay));
+    }
+
+    // Statistiques d'utilisation de chaque clé API déjà utilisée
+    pub fn stats(&self) -> Vec<(String, KeyStats)> {
+        self.stats
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect()
+    }
+
+    // Oublie les clés API inutilisées depuis `cutoff`, sauf celles encore en pause ou refusées
+    pub fn forget_keys_unused_since(&self, cutoff: SystemTime) {
+        let now = Instant::now();
+        self.invalid_keys
+            .retain(|_, invalid_since| invalid_since.elapsed() < INVALID_KEY_DURATION);
+        self.blocked_until.retain(|_, blocked_until| *blocked_until > now);
+        self.stats.retain(|api_key, stats| {
+            stats.last_used >= cutoff
+                || self.blocked_until.contains_key(api_key)
+                || self.invalid_keys.contains_key(api_key)
+        });
+        self.buckets.retain(|api_key, _| self.stats.contains_key(api_key));
+    }
+
+    pub fn forget_idle_keys(&self) {
+        if let Some(cutoff) = SystemTime::now().checked_sub(IDLE_KEY_DURATION) {
+            self.forget_keys_unused_since(cutoff);
+        }
+    }
+
+    pub fn mark_invalid(&self, api_key: String) {
+        self.invalid_keys.insert(api_key, Instant::now());
+    }
+
+    pub fn is_invalid(&self, api_key: &String) -> bool {
+        if let Some(invalid_since) = self.invalid_keys.get(api_key) {
+            return invalid_since.elapsed() < INVALID_KEY_DURATION;
+        }
+        false
+    }
+}
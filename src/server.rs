@@ -0,0 +1,82 @@
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::request::FromParam;
+use serde::Serialize;
+use std::fmt;
+
+// Serveurs NationsGlory acceptés par le proxy : un nom inconnu est refusé (400) avant d'atteindre l'API,
+// pour ne pas dépenser de jeton ni mettre une erreur en cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Server {
+    Blue,
+    Coral,
+    Orange,
+    Red,
+    Yellow,
+    Mocha,
+    White,
+    Black,
+    Cyan,
+    Lime,
+}
+
+impl Server {
+    pub const ALL: [Server; 10] = [
+        Server::Blue,
+        Server::Coral,
+        Server::Orange,
+        Server::Red,
+        Server::Yellow,
+        Server::Mocha,
+        Server::White,
+        Server::Black,
+        Server::Cyan,
+        Server::Lime,
+    ];
+
+    // Nom du serveur tel qu'attendu par l'API
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Server::Blue => "blue",
+            Server::Coral => "coral",
+            Server::Orange => "orange",
+            Server::Red => "red",
+            Server::Yellow => "yellow",
+            Server::Mocha => "mocha",
+            Server::White => "white",
+            Server::Black => "black",
+            Server::Cyan => "cyan",
+            Server::Lime => "lime",
+        }
+    }
+
+    // Serveur correspondant à un nom, sans tenir compte de la casse
+    pub fn from_name(name: &str) -> Option<Server> {
+        Server::ALL
+            .into_iter()
+            .find(|server| server.as_str().eq_ignore_ascii_case(name.trim()))
+    }
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Serveur passé dans le chemin (/hdv/<server>/list)
+impl<'a> FromParam<'a> for Server {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        Server::from_name(param).ok_or(param)
+    }
+}
+
+// Serveur passé dans la requête (?server=red)
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for Server {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Server::from_name(field.value).ok_or_else(|| form::Error::validation("unknown server").into())
+    }
+}
@@ -0,0 +1,72 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Orbit, Rocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// Signal d'arrêt partagé entre Rocket, les handlers et le worker
+#[derive(Debug)]
+pub struct Shutdown {
+    triggered: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            triggered: watch::Sender::new(false),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+
+    // Se termine dès que l'arrêt a été demandé
+    pub async fn triggered(&self) {
+        let mut receiver = self.triggered.subscribe();
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+// À l'arrêt de Rocket, prévient le worker et attend qu'il ait terminé les appels en cours
+pub struct ShutdownFairing {
+    pub shutdown: Arc<Shutdown>,
+    pub worker: Mutex<Option<JoinHandle<()>>>,
+    pub timeout: Duration, // Attente maximale du worker (il limite lui-même la durée des appels en cours)
+}
+
+#[rocket::async_trait]
+impl Fairing for ShutdownFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Worker shutdown",
+            kind: Kind::Shutdown,
+        }
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        tracing::info!("shutdown requested, draining the worker");
+        self.shutdown.trigger();
+        let worker = self
+            .worker
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if let Some(worker) = worker {
+            if tokio::time::timeout(self.timeout, worker).await.is_err() {
+                tracing::warn!("the worker did not stop in time");
+            }
+        }
+    }
+}
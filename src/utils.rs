@@ -1,68 +1,348 @@
-use dashmap::DashMap;
-use redis::AsyncCommands;
+use crate::cache::Cache;
+use crate::config::{Config, RoutePolicy};
+use crate::error::{set_request_error, ProxyError};
+use crate::logging::RequestId;
+use crate::metrics::Metrics;
+use crate::queue_limits::{queue_full, QueueLimits};
+use crate::rate_limit::{client_id, ApiKeyUsage};
+use crate::shutdown::Shutdown;
+use crate::waiters::Waiters;
+use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
 use rocket::serde::json::Json;
-use rocket::{Request, State};
+use rocket::Request;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::time::{Duration, Instant};
 use chrono::NaiveDate;
-use tokio::sync::{broadcast, mpsc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::Instrument;
 
-#[derive(Debug, Clone)]
+// Durée de conservation par défaut d'une réponse en cache
+pub const DEFAULT_CACHE_TIME: u64 = 1800;
+// Durée par défaut pendant laquelle une réponse expirée peut encore être servie (le temps de la rafraîchir)
+pub const DEFAULT_STALE_TIME: u64 = 60 * 60 * 24;
+// Durée pendant laquelle on évite de relancer le rafraîchissement d'une même réponse expirée
+const REFRESH_LOCK_TIME: u64 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueuedRequest {
+    pub route: String, // Nom de la route du proxy (voir Config::route), utilisé pour les métriques
+    pub request_id: String, // Identifiant de la requête du client à l'origine de l'appel, repris dans les logs du worker
     pub url: String,
     pub method: String,
     pub api_keys: Vec<String>,
-    pub cache_time: Option<u64>
+    pub cache_time: Option<u64>,
+    pub stale_time: Option<u64>,
+    pub background: bool, // Rafraîchissement lancé sans client en attente : il est exécuté même si personne n'attend
+    #[serde(default)]
+    pub priority: u8, // Les requêtes de plus haute priorité choisissent leur clé API en premier
+    #[serde(default)]
+    pub rejected_status: Option<u16>, // Statut (401/403) de la dernière clé API refusée par l'API
 }
 
 impl QueuedRequest {
+    // Requête GET d'un client pour la route `route` (voir Config::route) : la politique de cache et la priorité
+    // sont celles de la route, sauf si `cache_time` impose une autre durée de conservation
+    pub fn for_route(
+        proxy: &ProxyContext<'_>,
+        route: &str,
+        url: String,
+        api_keys: Vec<String>,
+        cache_time: Option<u64>,
+    ) -> Self {
+        let policy = proxy.config.route(route);
+        Self {
+            route: route.to_string(),
+            request_id: proxy.request_id.clone(),
+            url,
+            method: "GET".to_string(),
+            api_keys,
+            cache_time: cache_time.or(policy.cache_time),
+            stale_time: policy.stale_time,
+            background: false,
+            priority: proxy.priority(&policy),
+            rejected_status: None,
+        }
+    }
+
+    // Identifiant de la requête dans la file d'attente
+    pub fn key(&self) -> RequestKey {
+        (self.method.clone(), self.url.clone())
+    }
+
     // Fonction pour insérer une requête dans la file d'attente
     // Si une requête avec la même URL et le même verbe HTTP existe déjà, on ajoute des clés API à la requête existante afin de lui donner plus de chances d'être exécutée
     // Sinon, on ajoute la nouvelle requête à la file d'attente tout simplement
-    pub fn insert_request_to_queue(list: &mut Vec<QueuedRequest>, new_request: QueuedRequest) {
-        if let Some(existing) = list
-            .iter_mut()
-            .find(|req| req.url == new_request.url && req.method == new_request.method)
-        {
-            for key in new_request.api_keys {
-                if !existing.api_keys.contains(&key) {
-                    existing.api_keys.push(key);
-                }
+    // Renvoie true si la requête a été fusionnée avec une requête existante
+    pub fn insert_request_to_queue(list: &mut WaitingRequests, new_request: QueuedRequest) -> bool {
+        let key = new_request.key();
+        let client = client_id(&new_request.api_keys);
+        let Some(existing) = list.get_mut(&key) else {
+            list.push(new_request);
+            return false;
+        };
+        existing.background |= new_request.background;
+        existing.priority = existing.priority.max(new_request.priority);
+        existing.rejected_status = existing.rejected_status.or(new_request.rejected_status);
+        for key in new_request.api_keys {
+            if !existing.api_keys.contains(&key) {
+                existing.api_keys.push(key);
             }
+        }
+        list.add_client(&key, client);
+        true
+    }
+}
+
+// Identifiant d'une requête en attente : (verbe HTTP, URL)
+pub type RequestKey = (String, String);
+
+// Requête en attente et informations utilisées pour l'ordonnancement
+#[derive(Debug)]
+struct WaitingEntry {
+    sequence: u64, // Numéro d'arrivée
+    queued_at: Instant,
+    clients: Vec<String>, // Clients (jeux de clés API) ayant demandé cette requête
+    request: QueuedRequest,
+}
+
+// File d'attente des requêtes, indexée par (verbe HTTP, URL) et parcourue dans l'ordre d'arrivée
+// Les clients sont servis à tour de rôle : un client envoyant beaucoup de requêtes ne ralentit que lui-même
+#[derive(Debug, Default)]
+pub struct WaitingRequests {
+    requests: HashMap<RequestKey, WaitingEntry>,
+    order: BTreeMap<u64, RequestKey>,
+    next_sequence: u64,
+    served: HashMap<String, Instant>, // Dernière requête envoyée à l'API pour chaque client
+}
+
+impl WaitingRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn get(&self, key: &RequestKey) -> Option<&QueuedRequest> {
+        self.requests.get(key).map(|entry| &entry.request)
+    }
+
+    pub fn get_mut(&mut self, key: &RequestKey) -> Option<&mut QueuedRequest> {
+        self.requests.get_mut(key).map(|entry| &mut entry.request)
+    }
+
+    pub fn push(&mut self, request: QueuedRequest) {
+        let key = request.key();
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let entry = WaitingEntry {
+            sequence,
+            queued_at: Instant::now(),
+            clients: vec![client_id(&request.api_keys)],
+            request,
+        };
+        if let Some(old_entry) = self.requests.insert(key.clone(), entry) {
+            self.order.remove(&old_entry.sequence);
+        }
+        self.order.insert(sequence, key);
+    }
+
+    // Un autre client demande une requête déjà en attente
+    pub fn add_client(&mut self, key: &RequestKey, client: String) {
+        if let Some(entry) = self.requests.get_mut(key) {
+            if !entry.clients.contains(&client) {
+                entry.clients.push(client);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &RequestKey) -> Option<QueuedRequest> {
+        let entry = self.requests.remove(key)?;
+        self.order.remove(&entry.sequence);
+        Some(entry.request)
+    }
+
+    // Retire une requête de la file pour l'envoyer à l'API : ses clients passent en fin de tour
+    pub fn take_for_execution(&mut self, key: &RequestKey) -> Option<QueuedRequest> {
+        let entry = self.requests.remove(key)?;
+        self.order.remove(&entry.sequence);
+        let now = Instant::now();
+        for client in entry.clients {
+            self.served.insert(client, now);
+        }
+        // Un client servi avant l'arrivée de la plus ancienne requête n'a plus d'avantage ni de retard à faire valoir
+        if let Some(oldest_queued_at) = self.oldest_queued_at() {
+            self.served.retain(|_, served_at| *served_at >= oldest_queued_at);
         } else {
-            list.push(new_request);
+            self.served.clear();
         }
+        Some(entry.request)
+    }
+
+    // Identifiants des requêtes en attente, dans l'ordre d'arrivée
+    pub fn keys(&self) -> Vec<RequestKey> {
+        self.order.values().cloned().collect()
+    }
+
+    // Identifiants des requêtes en attente dans l'ordre où les envoyer à l'API :
+    // - par niveau de priorité décroissant : une requête gagne un niveau par période `aging` passée à attendre (0 pour désactiver),
+    //   sans compter le temps pendant lequel son client a été servi, pour ne jamais être affamée
+    // - à niveau égal, les clients sont servis à tour de rôle (la n-ième requête d'un client passe au n-ième tour),
+    //   en commençant par celui qui a été servi le moins récemment, puis dans l'ordre d'arrivée
+    pub fn keys_by_priority(&self, aging: Duration) -> Vec<RequestKey> {
+        let now = Instant::now();
+        let mut entries: Vec<(u64, Option<Instant>, &WaitingEntry)> = self
+            .requests
+            .values()
+            .map(|entry| {
+                // Dernier passage du client le moins bien servi (None s'il n'a jamais été servi)
+                let last_served = entry
+                    .clients
+                    .iter()
+                    .map(|client| self.served.get(client).copied())
+                    .min()
+                    .flatten();
+                let waited_since = last_served.map_or(entry.queued_at, |served_at| served_at.max(entry.queued_at));
+                let level = if aging.is_zero() {
+                    0
+                } else {
+                    (now.duration_since(waited_since).as_secs_f64() / aging.as_secs_f64()) as u64
+                };
+                (entry.request.priority as u64 + level, last_served, entry)
+            })
+            .collect();
+        entries.sort_by(|(a_level, _, a), (b_level, _, b)| b_level.cmp(a_level).then(a.sequence.cmp(&b.sequence)));
+
+        // Tour de chaque requête : nombre de requêtes du même client placées avant elle
+        let mut client_rounds: HashMap<&str, u64> = HashMap::new();
+        let mut keys: Vec<(u64, u64, Option<Instant>, u64, RequestKey)> = entries
+            .into_iter()
+            .map(|(level, last_served, entry)| {
+                let round = entry
+                    .clients
+                    .iter()
+                    .map(|client| {
+                        let round = client_rounds.entry(client).or_default();
+                        *round += 1;
+                        *round - 1
+                    })
+                    .min()
+                    .unwrap_or_default();
+                (level, round, last_served, entry.sequence, entry.request.key())
+            })
+            .collect();
+        keys.sort_by(|a, b| {
+            b.0.cmp(&a.0)
+                .then(a.1.cmp(&b.1))
+                .then(a.2.cmp(&b.2))
+                .then(a.3.cmp(&b.3))
+        });
+        keys.into_iter().map(|(_, _, _, _, key)| key).collect()
+    }
+
+    // Temps passé dans la file par une requête
+    pub fn wait_time(&self, key: &RequestKey) -> Option<Duration> {
+        Some(self.requests.get(key)?.queued_at.elapsed())
+    }
+
+    // Temps d'attente de la plus ancienne requête de la file
+    pub fn oldest_wait(&self) -> Option<Duration> {
+        self.wait_time(self.order.values().next()?)
+    }
+
+    fn oldest_queued_at(&self) -> Option<Instant> {
+        let key = self.order.values().next()?;
+        Some(self.requests.get(key)?.queued_at)
     }
 }
 
+// En-têtes de l'API recopiés dans la réponse du proxy
+pub const FORWARDED_HEADERS: [&str; 4] =
+    ["etag", "last-modified", "content-language", "retry-after"];
+
+// Réponse renvoyée au client : statut et en-têtes choisis de l'API, et corps JSON
 #[derive(Debug, Clone)]
-pub struct RequestResponse {
-    pub url: String,
-    pub method: String,
+pub struct ProxyResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
     pub body: Value,
+    pub cached_time: Option<chrono::DateTime<chrono::Utc>>, // Date exacte de l'appel à l'API dont la réponse est en cache
 }
 
-pub struct ApiKeyUsage {
-    last_usage: DashMap<String, Instant>, // Associe une clé API à son dernier usage
-}
+// En-têtes indiquant aux clients d'où vient la réponse et combien de temps elle a attendu
+pub const CACHE_STATUS_HEADER: &str = "X-Cache"; // HIT, MISS, STALE ou COALESCED
+pub const CACHE_EXPIRES_HEADER: &str = "X-Cache-Expires";
+pub const QUEUE_WAIT_HEADER: &str = "X-Queue-Wait-Ms";
+// Jetons restants sur l'ensemble des clés API du client
+pub const BUDGET_HEADER: &str = "X-RateLimit-Remaining";
 
-impl ApiKeyUsage {
-    pub fn new() -> Self {
+impl ProxyResponse {
+    pub fn ok(body: Value) -> Self {
         Self {
-            last_usage: DashMap::new(),
+            status: 200,
+            headers: Vec::new(),
+            body,
+            cached_time: None,
         }
     }
 
-    pub fn can_execute(&self, api_key: &String) -> bool {
-        if let Some(last_time) = self.last_usage.get(api_key) {
-            return last_time.elapsed() >= Duration::from_millis(500);
+    // Réponse tirée d'une entrée du cache ({"cached_time", "expires_time", "data"}) : `cache_status` vaut "HIT" ou "STALE"
+    pub fn from_cache(entry: &Value, cache_status: &str) -> Self {
+        let mut response = Self::ok(entry.get("data").cloned().unwrap_or(Value::Null));
+        response.set_header(CACHE_STATUS_HEADER, cache_status);
+        let parse_time = |field: &str| {
+            entry
+                .get(field)
+                .and_then(Value::as_str)
+                .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+                .map(|time| time.with_timezone(&chrono::Utc))
+        };
+        if let (Some(cached_time), Some(expires_time)) = (parse_time("cached_time"), parse_time("expires_time")) {
+            response.set_cache_times(cached_time, expires_time);
         }
-        true
+        response
     }
 
-    pub fn update_usage(&self, api_key: String) {
-        self.last_usage.insert(api_key, Instant::now());
+    // Âge de la réponse (en secondes) et date à laquelle elle expire du cache
+    pub fn set_cache_times(&mut self, cached_time: chrono::DateTime<chrono::Utc>, expires_time: chrono::DateTime<chrono::Utc>) {
+        let age = (chrono::Utc::now() - cached_time).num_seconds().max(0);
+        self.cached_time = Some(cached_time);
+        self.set_header("Age", age.to_string());
+        self.set_header(CACHE_EXPIRES_HEADER, expires_time.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+    }
+
+    // Ajoute un en-tête, ou remplace sa valeur s'il est déjà présent
+    pub fn set_header(&mut self, name: &str, value: impl Into<String>) {
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.into()));
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ProxyResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Json(self.body).respond_to(req)?;
+        response.set_status(Status::new(self.status));
+        for (name, value) in self.headers {
+            response.set_raw_header(name, value);
+        }
+        Ok(response)
     }
 }
 
@@ -70,7 +350,7 @@ pub struct ApiKeys(pub Vec<String>);
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for ApiKeys {
-    type Error = ();
+    type Error = ProxyError;
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         match req.headers().get_one("Authorization") {
@@ -78,42 +358,228 @@ impl<'r> FromRequest<'r> for ApiKeys {
                 let keys_vec = keys.split(',').map(String::from).collect();
                 Outcome::Success(ApiKeys(keys_vec))
             }
-            _ => Outcome::Error((rocket::http::Status::BadRequest, ())),
+            _ => {
+                set_request_error(req, ProxyError::MissingApiKey);
+                Outcome::Error((rocket::http::Status::BadRequest, ProxyError::MissingApiKey))
+            }
+        }
+    }
+}
+
+// Ressources partagées (gérées par Rocket) nécessaires pour interroger l'API via le proxy
+pub struct ProxyContext<'r> {
+    pub queue: &'r mpsc::Sender<QueuedRequest>,
+    pub cache: &'r Cache,
+    pub waiters: &'r Arc<Waiters>,
+    pub metrics: &'r Arc<Metrics>,
+    pub shutdown: &'r Arc<Shutdown>,
+    pub api_key_usage: &'r Arc<ApiKeyUsage>,
+    pub queue_limits: &'r Arc<QueueLimits>,
+    pub config: &'r Config,
+    pub request_id: String,
+    pub requested_priority: Option<u8>, // Priorité demandée avec l'en-tête X-Priority, ramenée dans les bornes autorisées
+}
+
+// En-tête permettant à un client de changer la priorité de sa requête (entre min_priority et max_priority)
+pub const PRIORITY_HEADER: &str = "X-Priority";
+
+impl ProxyContext<'_> {
+    // Priorité de la requête : celle demandée par le client, sinon celle de la route
+    pub fn priority(&self, route: &RoutePolicy) -> u8 {
+        self.requested_priority
+            .or(route.priority)
+            .unwrap_or_default()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ProxyContext<'r> {
+    type Error = ProxyError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let rocket = req.rocket();
+        match (
+            rocket.state::<mpsc::Sender<QueuedRequest>>(),
+            rocket.state::<Cache>(),
+            rocket.state::<Arc<Waiters>>(),
+            rocket.state::<Arc<Metrics>>(),
+            rocket.state::<Arc<Shutdown>>(),
+            rocket.state::<Arc<ApiKeyUsage>>(),
+            rocket.state::<Arc<QueueLimits>>(),
+            rocket.state::<Config>(),
+        ) {
+            (
+                Some(queue),
+                Some(cache),
+                Some(waiters),
+                Some(metrics),
+                Some(shutdown),
+                Some(api_key_usage),
+                Some(queue_limits),
+                Some(config),
+            ) => {
+                Outcome::Success(ProxyContext {
+                    queue,
+                    cache,
+                    waiters,
+                    metrics,
+                    shutdown,
+                    api_key_usage,
+                    queue_limits,
+                    config,
+                    request_id: RequestId::of(req),
+                    requested_priority: req
+                        .headers()
+                        .get_one(PRIORITY_HEADER)
+                        .and_then(|priority| priority.trim().parse::<u8>().ok())
+                        .map(|priority| priority.clamp(config.min_priority, config.max_priority.max(config.min_priority))),
+                })
+            }
+            _ => {
+                set_request_error(req, ProxyError::Internal);
+                Outcome::Error((rocket::http::Status::InternalServerError, ProxyError::Internal))
+            }
         }
     }
 }
 
 pub async fn api_request(
-    queue: &State<mpsc::Sender<QueuedRequest>>,
-    redis_client: &State<redis::Client>,
+    proxy: &ProxyContext<'_>,
+    request: QueuedRequest,
+) -> Result<ProxyResponse, ProxyError> {
+    let span = tracing::info_span!(
+        "api_request",
+        request_id = %proxy.request_id,
+        route = %request.route,
+        url = %request.url,
+        cache = tracing::field::Empty,
+    );
+    let started_at = Instant::now();
+    let api_keys = request.api_keys.clone();
+    let mut response = serve_request(proxy, request).instrument(span.clone()).await;
+    if let Ok(response) = &mut response {
+        let budget: f64 = api_keys
+            .iter()
+            .map(|api_key| proxy.api_key_usage.available_budget(api_key).floor())
+            .sum();
+        response.set_header(BUDGET_HEADER, budget.to_string());
+    }
+    let duration_ms = started_at.elapsed().as_millis() as u64;
+    span.in_scope(|| match &response {
+        Ok(response) => tracing::info!(status = response.status, duration_ms, "response ready"),
+        Err(error) => tracing::warn!(code = %error.code(), duration_ms, "request failed"),
+    });
+    response
+}
+
+async fn serve_request(
+    proxy: &ProxyContext<'_>,
     request: QueuedRequest,
-    response_broadcast_tx: &State<broadcast::Sender<RequestResponse>>,
-) -> Result<Json<Value>, rocket::http::Status> {
-    // Vérification du cache Redis
-    let mut redis_conn = redis_client
-        .get_multiplexed_async_connection()
-        .await
-        .unwrap();
+) -> Result<ProxyResponse, ProxyError> {
+    let ProxyContext {
+        queue,
+        cache,
+        waiters,
+        metrics,
+        shutdown,
+        api_key_usage,
+        queue_limits,
+        config,
+        ..
+    } = *proxy;
+    let cache_outcome = |outcome: &str| {
+        metrics.cache_outcome(outcome);
+        tracing::Span::current().record("cache", outcome);
+    };
+
+    // Vérification du cache (une erreur du cache n'empêche pas d'interroger l'API)
     let cache_key = format!("cache:{}", request.url);
-    if let Ok(cached_response) = redis_conn.get::<_, String>(&cache_key).await {
+    if let Ok(Some(cached_response)) = cache.get(&cache_key).await {
         if let Ok(json_value) = serde_json::from_str::<Value>(&cached_response) {
-            return Ok(Json(json_value));
+            let mut response = if is_stale(&json_value) {
+                // Réponse expirée : on la renvoie tout de suite et on la rafraîchit en arrière-plan
+                cache_outcome("stale");
+                refresh_in_background(queue, cache, request).await;
+                ProxyResponse::from_cache(&json_value, "STALE")
+            } else {
+                cache_outcome("hit");
+                ProxyResponse::from_cache(&json_value, "HIT")
+            };
+            response.set_header(QUEUE_WAIT_HEADER, "0");
+            return Ok(response);
         }
+        // Entrée illisible : on la supprime pour qu'elle soit remplacée par une réponse fraîche
+        tracing::warn!("unreadable cache entry, deleting it");
+        let _ = cache.delete(&cache_key).await;
+    }
+    cache_outcome("miss");
+
+    // Le proxy s'arrête : le worker ne prend plus de nouvelle requête
+    if shutdown.is_triggered() {
+        return Err(ProxyError::ShuttingDown {
+            retry_after: config.shutdown_retry_after,
+        });
     }
 
-    let url = request.url.clone();
-    let method = request.method.clone();
+    // On réserve une place dans la file : au-delà des limites, le client est invité à réessayer plus tard
+    let _slot = match queue_limits.try_acquire(&client_id(&request.api_keys)) {
+        Ok(slot) => slot,
+        Err(limit) => {
+            metrics.rejected_request(limit.as_str());
+            tracing::warn!(limit = limit.as_str(), "queue limit reached, request rejected");
+            return Err(queue_full(api_key_usage, &request.api_keys));
+        }
+    };
+
+    // On s'inscrit pour recevoir la réponse de cette requête. Tant que l'inscription existe, le worker sait que
+    // quelqu'un attend la réponse (elle disparaît si le client se déconnecte ou si le délai est dépassé, ce qui
+    // permet d'annuler l'appel à l'API)
+    let mut waiter = waiters.register(request.key());
+
+    let deadline = Duration::from_secs(config.request_timeout);
+    let response = tokio::time::timeout(deadline, async {
+        queue
+            .send(request)
+            .await
+            .map_err(|_| ProxyError::QueueUnavailable)?;
+        waiter.response().await
+    })
+    .await;
+    response.unwrap_or(Err(ProxyError::Timeout))
+}
 
-    let mut rx = response_broadcast_tx.subscribe();
+// Une réponse en cache est périmée une fois sa date d'expiration passée (elle reste servie pendant la durée `stale_time`)
+pub fn is_stale(cached_response: &Value) -> bool {
+    cached_response
+        .get("expires_time")
+        .and_then(Value::as_str)
+        .and_then(|expires_time| chrono::DateTime::parse_from_rfc3339(expires_time).ok())
+        .is_some_and(|expires_time| expires_time <= chrono::Utc::now())
+}
 
-    queue.send(request).await.unwrap();
+// Renvoie la dernière réponse connue pour cette URL, marquée comme périmée
+pub async fn get_stale_response(cache: &Cache, url: &str) -> Option<ProxyResponse> {
+    let cached_response = cache.get(&format!("cache:{}", url)).await.ok()??;
+    let json_value = serde_json::from_str::<Value>(&cached_response).ok()?;
+    Some(ProxyResponse::from_cache(&json_value, "STALE"))
+}
 
-    while let Ok(response) = rx.recv().await {
-        if response.url == url && response.method == method {
-            return Ok(Json(response.body));
+// Ajoute la requête à la file d'attente sans attendre sa réponse, une seule fois par période REFRESH_LOCK_TIME
+async fn refresh_in_background(
+    queue: &mpsc::Sender<QueuedRequest>,
+    cache: &Cache,
+    mut request: QueuedRequest,
+) {
+    request.background = true;
+    let refresh_key = format!("refresh:{}", request.url);
+    if let Ok(None) = cache.get(&refresh_key).await {
+        // Si la file d'attente est pleine, le rafraîchissement sera retenté au prochain appel
+        if queue.try_send(request).is_ok() {
+            let _ = cache
+                .set(&refresh_key, "1".to_string(), REFRESH_LOCK_TIME)
+                .await;
         }
     }
-    Err(rocket::http::Status::InternalServerError)
 }
 
 pub fn get_week_number_from_date(date: NaiveDate) -> i64 {
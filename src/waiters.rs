@@ -0,0 +1,141 @@
+use crate::error::ProxyError;
+use crate::utils::{ProxyResponse, RequestKey, CACHE_STATUS_HEADER, QUEUE_WAIT_HEADER};
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{oneshot, Notify};
+
+pub type WaiterResponse = Result<ProxyResponse, ProxyError>;
+
+// Clients attendant la réponse d'une même requête (verbe HTTP, URL)
+#[derive(Debug)]
+struct WaiterList {
+    senders: Vec<(u64, Instant, oneshot::Sender<WaiterResponse>)>, // Identifiant, date d'inscription et canal de chaque client
+    abandoned: Arc<Notify>, // Signal envoyé lorsque le dernier client en attente s'en va
+    dispatched_at: Option<Instant>, // Dernier envoi de la requête à l'API
+}
+
+// Clients actuellement en attente de la réponse de chaque requête : chaque réponse n'est envoyée qu'à ses propres clients
+// Permet aussi au worker de ne pas dépenser une clé API pour une réponse que plus personne n'attend
+#[derive(Debug, Default)]
+pub struct Waiters {
+    entries: DashMap<RequestKey, WaiterList>,
+    next_id: AtomicU64,
+}
+
+impl Waiters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Enregistre un client en attente : il est retiré automatiquement lorsque le `Waiter` est détruit
+    // (réponse reçue, délai dépassé ou client déconnecté)
+    pub fn register(self: &Arc<Self>, key: RequestKey) -> Waiter {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.entries
+            .entry(key.clone())
+            .or_insert_with(|| WaiterList {
+                senders: Vec::new(),
+                abandoned: Arc::new(Notify::new()),
+                dispatched_at: None,
+            })
+            .senders
+            .push((id, Instant::now(), sender));
+        Waiter {
+            waiters: self.clone(),
+            key,
+            id,
+            receiver,
+        }
+    }
+
+    pub fn has_waiters(&self, key: &RequestKey) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    // Le worker envoie la requête à l'API : le temps d'attente dans la file des clients s'arrête là
+    pub fn dispatched(&self, key: &RequestKey) {
+        if let Some(mut list) = self.entries.get_mut(key) {
+            list.dispatched_at = Some(Instant::now());
+        }
+    }
+
+    // Envoie la réponse à tous les clients qui l'attendent, avec le temps que chacun a passé dans la file
+    pub fn respond(&self, key: &RequestKey, response: WaiterResponse) {
+        // Personne n'attend peut-être plus la réponse (rafraîchissement en arrière-plan) : ce n'est pas une erreur
+        let Some((_, list)) = self.entries.remove(key) else {
+            return;
+        };
+        let dispatched_at = list.dispatched_at.unwrap_or_else(Instant::now);
+        for (index, (_, registered_at, sender)) in list.senders.into_iter().enumerate() {
+            let response = response.clone().map(|mut response| {
+                let queue_wait = dispatched_at.saturating_duration_since(registered_at);
+                response.set_header(QUEUE_WAIT_HEADER, queue_wait.as_millis().to_string());
+                // Seul le premier client a déclenché l'appel à l'API : les suivants ont profité de sa réponse
+                if index > 0 && response.header(CACHE_STATUS_HEADER) == Some("MISS") {
+                    response.set_header(CACHE_STATUS_HEADER, "COALESCED");
+                }
+                response
+            });
+            let _ = sender.send(response);
+        }
+    }
+
+    // Envoie la même réponse à tous les clients en attente, quelle que soit leur requête (arrêt du proxy)
+    pub fn respond_all(&self, response: WaiterResponse) {
+        let keys: Vec<RequestKey> = self.entries.iter().map(|entry| entry.key().clone()).collect();
+        for key in keys {
+            self.respond(&key, response.clone());
+        }
+    }
+
+    // Se termine dès que plus aucun client n'attend la réponse de la requête
+    pub async fn abandoned(&self, key: &RequestKey) {
+        loop {
+            let Some(notify) = self.entries.get(key).map(|list| list.abandoned.clone()) else {
+                return;
+            };
+            let notified = notify.notified();
+            tokio::pin!(notified);
+            // On s'abonne avant de vérifier, pour ne pas manquer un départ survenu entre-temps
+            notified.as_mut().enable();
+            if !self.has_waiters(key) {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn unregister(&self, key: &RequestKey, id: u64) {
+        let removed = self.entries.remove_if_mut(key, |_, list| {
+            list.senders.retain(|(sender_id, _, _)| *sender_id != id);
+            list.senders.is_empty()
+        });
+        if let Some((_, list)) = removed {
+            list.abandoned.notify_waiters();
+        }
+    }
+}
+
+pub struct Waiter {
+    waiters: Arc<Waiters>,
+    key: RequestKey,
+    id: u64,
+    receiver: oneshot::Receiver<WaiterResponse>,
+}
+
+impl Waiter {
+    pub async fn response(&mut self) -> WaiterResponse {
+        (&mut self.receiver)
+            .await
+            .unwrap_or(Err(ProxyError::Internal))
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.waiters.unregister(&self.key, self.id);
+    }
+}
@@ -1,154 +1,471 @@
-use crate::utils::{ApiKeyUsage, QueuedRequest, RequestResponse};
-use redis::AsyncCommands;
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::health::{Health, WORKER_HEARTBEAT_INTERVAL};
+use crate::metrics::Metrics;
+use crate::rate_limit::{key_id, ApiKeyUsage, RateLimitHeaders};
+use crate::shutdown::Shutdown;
+use crate::waiters::Waiters;
+use crate::utils::{
+    get_stale_response, ProxyResponse, QueuedRequest, WaitingRequests, CACHE_STATUS_HEADER,
+    DEFAULT_CACHE_TIME, DEFAULT_STALE_TIME, FORWARDED_HEADERS,
+};
+use reqwest::StatusCode;
 use serde_json::{json, Value};
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::{broadcast, mpsc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::Instrument;
 
+// Clé du cache dans laquelle la file d'attente est sauvegardée à l'arrêt (option persist_queue)
+const PERSISTED_QUEUE_KEY: &str = "queue:pending";
+// Durée de conservation de la file d'attente sauvegardée
+const PERSISTED_QUEUE_TTL: u64 = 60 * 60 * 24;
+
+#[allow(clippy::too_many_arguments)]
 pub async fn process_requests_v2(
     mut queue_rx: mpsc::Receiver<QueuedRequest>,
-    response_broadcast_tx: broadcast::Sender<RequestResponse>,
     api_key_usage: Arc<ApiKeyUsage>,
-    redis_client: redis::Client,
+    cache: Cache,
+    waiters: Arc<Waiters>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    shutdown: Arc<Shutdown>,
+    config: Config,
 ) {
-    let client = reqwest::Client::new();
-    let mut waiting_requests: Vec<QueuedRequest> = Vec::new();
-    let used_keys = Arc::new(Mutex::new(HashSet::new()));
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.upstream_timeout))
+        .build()
+        .unwrap_or_default();
+    let mut waiting_requests = WaitingRequests::new();
+    if config.persist_queue {
+        // Les requêtes sauvegardées au dernier arrêt n'ont plus de client : elles servent à remplir le cache
+        for request in load_persisted_queue(&cache).await {
+            insert_request(&mut waiting_requests, request, &metrics);
+        }
+    }
+    // Appels à l'API en cours, attendus à l'arrêt du worker
+    let mut in_flight = JoinSet::new();
+    let mut used_keys: HashSet<String> = HashSet::new();
+    // File interne permettant de remettre en attente une requête dont la clé API a été refusée
+    let (retry_tx, mut retry_rx) = mpsc::unbounded_channel();
+    // File interne par laquelle les requêtes terminées libèrent leur clé API
+    let (released_key_tx, mut released_key_rx) = mpsc::unbounded_channel::<String>();
+    // Dates auxquelles une clé API attendue par une requête redevient utilisable (la plus proche en tête)
+    let mut timers: BinaryHeap<Reverse<(Instant, String)>> = BinaryHeap::new();
+    let mut scheduled_keys: HashMap<String, Instant> = HashMap::new();
+    // Durée d'attente faisant gagner un niveau de priorité à une requête
+    let aging = Duration::from_secs(config.priority_aging);
+    // Réveil régulier permettant de signaler à /readyz que le worker est toujours en vie
+    let mut heartbeat = tokio::time::interval(WORKER_HEARTBEAT_INTERVAL);
 
     loop {
-        // On commence par traiter les requêtes en attente
-        received_queue(&mut queue_rx, &mut waiting_requests);
+        // On dort jusqu'à l'arrivée d'une requête, la libération d'une clé API ou la prochaine échéance d'une clé API
+        let next_timer = timers.peek().map(|Reverse((instant, _))| *instant);
+        tokio::select! {
+            request = queue_rx.recv() => match request {
+                Some(request) => insert_request(&mut waiting_requests, request, &metrics),
+                None => break, // Plus personne ne peut envoyer de requête
+            },
+            Some(request) = retry_rx.recv() => insert_request(&mut waiting_requests, request, &metrics),
+            Some(api_key) = released_key_rx.recv() => {
+                used_keys.remove(&api_key);
+            }
+            _ = heartbeat.tick() => api_key_usage.forget_idle_keys(),
+            Some(_) = in_flight.join_next() => {}
+            _ = shutdown.triggered() => break,
+            _ = tokio::time::sleep_until(next_timer.unwrap_or_else(Instant::now)), if next_timer.is_some() => {
+                let now = Instant::now();
+                while let Some(Reverse((instant, api_key))) = timers.peek() {
+                    if *instant > now {
+                        break;
+                    }
+                    if scheduled_keys.get(api_key) == Some(instant) {
+                        scheduled_keys.remove(api_key);
+                    }
+                    timers.pop();
+                }
+            }
+        }
+
+        // On récupère tout ce qui est déjà arrivé afin de traiter les évènements par lot
+        received_queue(&mut queue_rx, &mut retry_rx, &mut waiting_requests, &metrics);
+        while let Ok(api_key) = released_key_rx.try_recv() {
+            used_keys.remove(&api_key);
+        }
 
         // On traite les requêtes en attente: on vérifie lequel peuvent être executé puis on les exécuter dans un nouveau thread.
         // On se doit de veiller à ce que nous sélectionnons qu'une clé API par requête
-        {
-            let mut remaining_requests = Vec::new(); // Liste temporaire pour stocker les requêtes non exécutées
-
-            for request in waiting_requests.drain(..) {
-                let mut executed = false;
-                for api_key in request.api_keys.clone() {
-                    if !used_keys.lock().await.contains(&api_key)
-                        && api_key_usage.can_execute(&api_key)
-                    {
-                        used_keys.lock().await.insert(api_key.clone());
-                        executed = true;
-                        // On exécute la requête dans un thread séparé
-                        tokio::spawn({
-                            let used_keys = used_keys.clone();
-                            let request = request.clone();
-                            let api_key = api_key.clone();
-                            let redis_client = redis_client.clone();
-                            let client = client.clone();
-                            let response_broadcast_tx = response_broadcast_tx.clone();
-                            let api_key_usage = api_key_usage.clone();
-                            let used_keys = used_keys.clone();
-                            async move {
-                                execute_request(
-                                    request,
-                                    api_key.clone(),
-                                    redis_client,
-                                    client,
-                                    response_broadcast_tx,
-                                    api_key_usage,
-                                    used_keys,
-                                )
-                                .await;
-                            }
-                        });
-                        break;
+        for request_key in waiting_requests.keys_by_priority(aging) {
+            let Some(request) = waiting_requests.get_mut(&request_key) else {
+                continue;
+            };
+
+            // Plus personne n'attend la réponse (clients déconnectés ou délai dépassé) : on abandonne la requête
+            if !request.background && !waiters.has_waiters(&request_key) {
+                tracing::debug!(request_id = %request.request_id, url = %request.url, "nobody is waiting anymore, request dropped");
+                waiting_requests.remove(&request_key);
+                continue;
+            }
+
+            // On retire les clés API que l'API a déjà refusées
+            request
+                .api_keys
+                .retain(|api_key| !api_key_usage.is_invalid(api_key));
+            if request.api_keys.is_empty() {
+                // Plus aucune clé API utilisable : on renvoie une erreur à ceux qui attendent
+                tracing::warn!(request_id = %request.request_id, url = %request.url, "every API key was rejected");
+                waiters.respond(
+                    &request_key,
+                    Err(ProxyError::InvalidApiKey {
+                        upstream_status: request.rejected_status,
+                    }),
+                );
+                waiting_requests.remove(&request_key);
+                continue;
+            }
+
+            // On choisit, parmi les clés libres, celle qui dispose du plus de jetons
+            // (à budget égal, la première dans l'ordre donné par le client)
+            let selected_key = request
+                .api_keys
+                .iter()
+                .rev()
+                .filter(|api_key| !used_keys.contains(*api_key))
+                .map(|api_key| (api_key, api_key_usage.available_budget(api_key)))
+                .filter(|(_, budget)| *budget >= 1.0)
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(api_key, _)| api_key.clone());
+
+            let Some(api_key) = selected_key else {
+                // Aucune clé disponible : on programme un réveil pour les clés libres mais à court de jetons
+                // (les clés occupées nous réveilleront d'elles-mêmes en étant libérées)
+                for api_key in request.api_keys.iter().filter(|key| !used_keys.contains(*key)) {
+                    let available_at = Instant::from_std(api_key_usage.next_available(api_key));
+                    let already_scheduled = scheduled_keys
+                        .get(api_key)
+                        .is_some_and(|instant| *instant <= available_at);
+                    if !already_scheduled {
+                        scheduled_keys.insert(api_key.clone(), available_at);
+                        timers.push(Reverse((available_at, api_key.clone())));
                     }
                 }
-                if !executed {
-                    remaining_requests.push(request);
+                continue;
+            };
+
+            let wait_ms = waiting_requests
+                .wait_time(&request_key)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            let Some(request) = waiting_requests.take_for_execution(&request_key) else {
+                continue;
+            };
+            waiters.dispatched(&request_key);
+            used_keys.insert(api_key.clone());
+            // Les clés API n'apparaissent dans les logs que sous la forme d'un hash court
+            let span = tracing::info_span!(
+                "execute_request",
+                request_id = %request.request_id,
+                route = %request.route,
+                url = %request.url,
+                key = %key_id(&api_key),
+                key_slot = request.api_keys.iter().position(|key| key == &api_key),
+                wait_ms,
+            );
+            // On exécute la requête dans un thread séparé
+            let execution = tokio::spawn(execute_request(
+                request,
+                api_key.clone(),
+                cache.clone(),
+                client.clone(),
+                api_key_usage.clone(),
+                waiters.clone(),
+                metrics.clone(),
+                health.clone(),
+                released_key_tx.clone(),
+                retry_tx.clone(),
+            ).instrument(span));
+            // Si l'exécution panique, on libère quand même la clé et on prévient ceux qui attendent
+            in_flight.spawn({
+                let waiters = waiters.clone();
+                let released_key_tx = released_key_tx.clone();
+                async move {
+                    if execution.await.is_err() {
+                        tracing::error!(url = %request_key.1, "upstream call panicked");
+                        let _ = released_key_tx.send(api_key);
+                        waiters.respond(&request_key, Err(ProxyError::Internal));
+                    }
                 }
+            });
+        }
+
+        metrics.queue_depth.set(waiting_requests.len() as i64);
+        metrics.keys_in_use.set(used_keys.len() as i64);
+        health.worker_heartbeat(waiting_requests.len(), waiting_requests.oldest_wait());
+    }
+
+    // Arrêt : on ne prend plus de nouvelle requête, on répond 503 à celles en attente et on laisse les appels en cours
+    // se terminer (et écrire dans le cache) pendant au plus `shutdown_timeout`
+    shutdown.trigger();
+    queue_rx.close();
+    received_queue(&mut queue_rx, &mut retry_rx, &mut waiting_requests, &metrics);
+    let shutting_down = ProxyError::ShuttingDown {
+        retry_after: config.shutdown_retry_after,
+    };
+    tracing::info!(
+        queued = waiting_requests.len(),
+        in_flight = in_flight.len(),
+        "worker stopping"
+    );
+    if config.persist_queue {
+        persist_queue(&cache, &waiting_requests).await;
+    }
+    for request_key in waiting_requests.keys() {
+        waiters.respond(&request_key, Err(shutting_down.clone()));
+    }
+    let drain = async { while in_flight.join_next().await.is_some() {} };
+    if tokio::time::timeout(Duration::from_secs(config.shutdown_timeout), drain)
+        .await
+        .is_err()
+    {
+        tracing::warn!(in_flight = in_flight.len(), "upstream calls still running at shutdown");
+    }
+    // Les clients des appels non terminés (ou remis en attente entre-temps) reçoivent aussi une 503
+    waiters.respond_all(Err(shutting_down));
+}
+
+async fn persist_queue(cache: &Cache, waiting_requests: &WaitingRequests) {
+    let requests: Vec<&QueuedRequest> = waiting_requests
+        .keys()
+        .iter()
+        .filter_map(|key| waiting_requests.get(key))
+        .collect();
+    if requests.is_empty() {
+        return;
+    }
+    match serde_json::to_string(&requests) {
+        Ok(value) => {
+            if let Err(error) = cache.set(PERSISTED_QUEUE_KEY, value, PERSISTED_QUEUE_TTL).await {
+                tracing::warn!(%error, "unable to persist the queue");
             }
-            waiting_requests = remaining_requests;
         }
+        Err(error) => tracing::warn!(%error, "unable to serialize the queue"),
+    }
+}
 
-        // On attend 10ms avant de continuer (valeur arbitraire)
-        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+async fn load_persisted_queue(cache: &Cache) -> Vec<QueuedRequest> {
+    let Ok(Some(value)) = cache.get(PERSISTED_QUEUE_KEY).await else {
+        return Vec::new();
+    };
+    let _ = cache.delete(PERSISTED_QUEUE_KEY).await;
+    let mut requests: Vec<QueuedRequest> = serde_json::from_str(&value).unwrap_or_default();
+    for request in &mut requests {
+        request.background = true;
     }
+    tracing::info!(count = requests.len(), "persisted queue restored");
+    requests
 }
 
 pub fn received_queue(
     queue_rx: &mut mpsc::Receiver<QueuedRequest>,
-    waiting_requests: &mut Vec<QueuedRequest>,
+    retry_rx: &mut mpsc::UnboundedReceiver<QueuedRequest>,
+    waiting_requests: &mut WaitingRequests,
+    metrics: &Metrics,
 ) {
+    while let Ok(request) = retry_rx.try_recv() {
+        insert_request(waiting_requests, request, metrics);
+    }
     while let Ok(request) = queue_rx.try_recv() {
-        QueuedRequest::insert_request_to_queue(waiting_requests, request);
+        insert_request(waiting_requests, request, metrics);
     }
 }
 
-// Attention ! Le fonctionnement actuel fait que si quelqu'un envoie une requête avec une clé API invalide, la requête retournera une erreur pour tout le monde !
-// TODO: Ajouter un système pour remettre la requête dans la file d'attente si une clé API est invalide et qu'il reste des clés API à essayer
+fn insert_request(waiting_requests: &mut WaitingRequests, request: QueuedRequest, metrics: &Metrics) {
+    let request_id = request.request_id.clone();
+    let url = request.url.clone();
+    if QueuedRequest::insert_request_to_queue(waiting_requests, request) {
+        tracing::debug!(%request_id, %url, "request coalesced with a queued request");
+        metrics.coalesced_requests.inc();
+    }
+}
+
+// Si l'API refuse la clé API utilisée (401/403), la clé est marquée comme invalide et la requête est remise en attente
+// avec les clés API restantes. L'erreur n'est renvoyée aux clients qu'une fois toutes les clés API essayées.
+// Si l'API renvoie une 429, la clé est mise en pause (Retry-After) et la requête est remise en attente.
+// Si plus aucun client n'attend la réponse pendant l'appel, celui-ci est annulé et la clé API libérée.
+#[allow(clippy::too_many_arguments)]
 pub async fn execute_request(
-    request: QueuedRequest,
+    mut request: QueuedRequest,
     api_key: String,
-    redis_client: redis::Client,
+    cache: Cache,
     request_client: reqwest::Client,
-    response_broadcast_tx: broadcast::Sender<RequestResponse>,
     api_key_usage: Arc<ApiKeyUsage>,
-    used_key: Arc<Mutex<HashSet<String>>>,
+    waiters: Arc<Waiters>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    released_key_tx: mpsc::UnboundedSender<String>,
+    retry_tx: mpsc::UnboundedSender<QueuedRequest>,
 ) {
     let url = request.url.clone();
-    let method = request.method.clone();
+    let request_key = request.key();
+    let respond = |body: Result<ProxyResponse, ProxyError>| waiters.respond(&request_key, body);
 
-    let response = request_client
-        .request(method.parse().unwrap(), &url)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .send()
-        .await;
+    let Ok(http_method) = request.method.parse::<reqwest::Method>() else {
+        let _ = released_key_tx.send(api_key);
+        respond(Err(ProxyError::Internal));
+        return;
+    };
+
+    let started_at = Instant::now();
+    let response = tokio::select! {
+        response = request_client
+            .request(http_method, &url)
+            .header("Authorization", format!("Bearer {}", api_key))
+            .send() => response,
+        _ = waiters.abandoned(&request_key), if !request.background => {
+            tracing::info!("nobody is waiting anymore, upstream call cancelled");
+            // L'appel a peut-être déjà atteint l'API : on le compte dans le budget de la clé
+            api_key_usage.update_usage(api_key.clone());
+            let _ = released_key_tx.send(api_key);
+            return;
+        }
+    };
 
     api_key_usage.update_usage(api_key.clone());
-    used_key.lock().await.remove(&api_key);
-
-    match response {
-        Ok(resp) => {
-            let body_text = resp
-                .text()
-                .await
-                .unwrap_or_else(|_| json!({"error": "Failed to read response"}).to_string());
-            let body: Value = serde_json::from_str(&body_text).unwrap_or_else(
-                |_| json!({"error": "Failed to parse response", "message": body_text}),
-            );
+    let status_label = match &response {
+        Ok(resp) => resp.status().as_u16().to_string(),
+        Err(error) if error.is_timeout() => "timeout".to_string(),
+        Err(_) => "error".to_string(),
+    };
+    let latency = started_at.elapsed();
+    metrics.upstream_response(&request.route, &status_label, latency);
+    tracing::info!(status = %status_label, latency_ms = latency.as_millis() as u64, "upstream responded");
+    if let Ok(resp) = &response {
+        let rate_limit = RateLimitHeaders::from_headers(resp.headers());
+        api_key_usage.update_budget(&api_key, &rate_limit);
+        match resp.status() {
+            StatusCode::TOO_MANY_REQUESTS => api_key_usage.block(&api_key, &rate_limit),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                api_key_usage.mark_invalid(api_key.clone())
+            }
+            _ => {}
+        }
+    }
+    // La clé API est de nouveau libre : on prévient le worker
+    let _ = released_key_tx.send(api_key.clone());
 
-            {
-                let body = json!({"cached": false, "data": body});
-                response_broadcast_tx
-                    .send(RequestResponse {
-                        url: url.clone(),
-                        method: method.clone(),
-                        body: body.clone(),
-                    })
-                    .expect("TODO: panic message");
+    let resp = match response {
+        Ok(resp) => resp,
+        Err(error) => {
+            // L'API est injoignable : on renvoie la dernière réponse connue si on en a une
+            tracing::warn!(%error, "upstream unreachable");
+            match get_stale_response(&cache, &url).await {
+                Some(stale_response) => respond(Ok(stale_response)),
+                None if error.is_timeout() => respond(Err(ProxyError::Timeout)),
+                None => respond(Err(ProxyError::UpstreamUnavailable)),
             }
+            return;
+        }
+    };
+
+    let resp_status = resp.status();
+    if resp_status == StatusCode::TOO_MANY_REQUESTS {
+        // La clé API a atteint son quota (elle a été mise en pause) : on remet la requête en attente sans erreur
+        tracing::warn!("API key rate limited, request queued again");
+        if retry_tx.send(request.clone()).is_ok() {
+            return;
+        }
+    }
+
+    if matches!(resp_status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        tracing::warn!("API key rejected by the upstream");
+        request.api_keys.retain(|key| key != &api_key);
+        request.rejected_status = Some(resp_status.as_u16());
+        // La requête est remise dans la file même sans clé restante : elle y est fusionnée avec celle d'un autre client
+        // arrivé entre-temps avec ses propres clés, et l'erreur n'est renvoyée que si plus aucune clé ne reste à essayer
+        if retry_tx.send(request.clone()).is_err() {
+            respond(Err(ProxyError::InvalidApiKey {
+                upstream_status: Some(resp_status.as_u16()),
+            }));
+        }
+        return;
+    }
 
-            if body.get("error").is_some() {
-                // Si l'API a renvoyé une erreur, on ne cache pas la réponse
+    let forwarded_headers: Vec<(String, String)> = FORWARDED_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = resp.headers().get(*name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    let body = match resp.text().await {
+        Ok(body_text) => serde_json::from_str::<Value>(&body_text).map_err(|_| {
+            ProxyError::InvalidUpstreamResponse {
+                upstream_status: resp_status.as_u16(),
+            }
+        }),
+        Err(error) if error.is_timeout() => Err(ProxyError::Timeout),
+        Err(_) => Err(ProxyError::UpstreamUnavailable),
+    };
+    let body = match body {
+        Ok(body) if !resp_status.is_server_error() => body,
+        result => {
+            // L'API est en difficulté : on renvoie la dernière réponse connue si on en a une
+            tracing::warn!(status = resp_status.as_u16(), "upstream error or unreadable response");
+            if let Some(stale_response) = get_stale_response(&cache, &url).await {
+                respond(Ok(stale_response));
                 return;
             }
-            let mut redis_conn = redis_client
-                .get_multiplexed_async_connection()
-                .await
-                .unwrap();
-            let cache_key = format!("cache:{}", url);
-            {
-                let actual_time = chrono::Utc::now().to_rfc3339();
-                let body = json!({"cached": true, "cached_time": actual_time,"data": body});
-                let _: () = redis_conn
-                    .set_ex(cache_key, body.to_string(), request.cache_time.unwrap_or(1800))
-                    .await
-                    .unwrap();
+            match result {
+                Ok(body) => body,
+                Err(error) => {
+                    respond(Err(error));
+                    return;
+                }
             }
         }
-        Err(_) => {
-            response_broadcast_tx
-                .send(RequestResponse {
-                    url: url.clone(),
-                    method: method.clone(),
-                    body: json!({"error": "API request failed"}),
-                })
-                .expect("TODO: panic message");
-        }
+    };
+
+    // On met la réponse en cache avant de la diffuser, pour qu'un appel suivant la trouve forcément
+    // Seules les réponses 2xx sont mises en cache (une 404 ou une erreur ne doit pas rester en cache)
+    if resp_status.is_success() {
+        health.upstream_success();
+    }
+    let mut response = ProxyResponse {
+        status: resp_status.as_u16(),
+        headers: forwarded_headers,
+        body,
+        cached_time: None,
+    };
+    response.set_header(CACHE_STATUS_HEADER, "MISS");
+    let cacheable = resp_status.is_success() && response.body.get("error").is_none();
+    if cacheable {
+        let cache_key = format!("cache:{}", url);
+        // La réponse est fraîche pendant `cache_time`, puis peut encore être servie pendant `stale_time`
+        let cache_time = request.cache_time.unwrap_or(DEFAULT_CACHE_TIME);
+        let stale_time = request.stale_time.unwrap_or(DEFAULT_STALE_TIME);
+        let actual_time = chrono::Utc::now();
+        // Une durée démesurée (configuration) ne doit pas faire paniquer le calcul de la date d'expiration
+        let expires_time = i64::try_from(cache_time)
+            .ok()
+            .and_then(chrono::Duration::try_seconds)
+            .and_then(|cache_time| actual_time.checked_add_signed(cache_time))
+            .unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC);
+        let entry = json!({
+            "cached_time": actual_time.to_rfc3339(),
+            "expires_time": expires_time.to_rfc3339(),
+            "data": response.body
+        });
+        // Une erreur du cache n'empêche pas de répondre
+        let _ = cache
+            .set(&cache_key, entry.to_string(), cache_time.saturating_add(stale_time))
+            .await;
+        response.set_cache_times(actual_time, expires_time);
     }
+
+    respond(Ok(response));
 }
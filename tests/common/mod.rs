@@ -0,0 +1,57 @@
+// Outils partagés par les tests d'intégration : un faux serveur NationsGlory et le proxy branché dessus
+#![allow(dead_code)]
+
+use nationsglory_api_proxy::build_rocket;
+use nationsglory_api_proxy::config::Config;
+use rocket::figment::Figment;
+use rocket::local::asynchronous::Client;
+use serde_json::Value;
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+// Configuration du proxy pointant vers le faux serveur, avec un cache et un historique en mémoire et des clés API sans limite
+pub fn test_config(upstream: &MockServer) -> Config {
+    Config {
+        upstream_base_url: upstream.uri(),
+        cache_backend: Some("memory".to_string()),
+        api_key_rate_limit: Some("100/100".to_string()),
+        history_path: ":memory:".to_string(),
+        ..Config::default()
+    }
+}
+
+pub async fn start_proxy(config: Config) -> Client {
+    let rocket = build_rocket(config).expect("valid cache configuration");
+    // On coupe les logs de Rocket pour garder une sortie de test lisible
+    let figment = Figment::from(rocket.figment()).merge(("log_level", "off"));
+    Client::tracked(rocket.configure(figment))
+        .await
+        .expect("valid rocket instance")
+}
+
+pub async fn get_json(client: &Client, uri: &str, api_keys: &str) -> (u16, Value) {
+    let response = client
+        .get(uri.to_string())
+        .header(rocket::http::Header::new("Authorization", api_keys.to_string()))
+        .dispatch()
+        .await;
+    let status = response.status().code;
+    let body = response.into_json::<Value>().await.unwrap_or(Value::Null);
+    (status, body)
+}
+
+// Réponse JSON du faux serveur, éventuellement retardée pour simuler la latence de l'API
+pub fn json_response(body: Value, latency: Duration) -> ResponseTemplate {
+    ResponseTemplate::new(200)
+        .set_body_json(body)
+        .set_delay(latency)
+}
+
+pub async fn mount_json(upstream: &MockServer, route: &str, body: Value) {
+    Mock::given(method("GET"))
+        .and(path(route))
+        .respond_with(json_response(body, Duration::ZERO))
+        .mount(upstream)
+        .await;
+}
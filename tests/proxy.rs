@@ -0,0 +1,1417 @@
+mod common;
+
+use common::{get_json, json_response, mount_json, start_proxy, test_config};
+use chrono::{TimeZone, Utc};
+use nationsglory_api_proxy::build_rocket;
+use nationsglory_api_proxy::cache::Cache;
+use nationsglory_api_proxy::config::RoutePolicy;
+use nationsglory_api_proxy::history::{retention_cutoff, History, NormalizedListing};
+use nationsglory_api_proxy::metrics::Metrics;
+use nationsglory_api_proxy::models::User;
+use nationsglory_api_proxy::rate_limit::{
+    key_id, parse_reset, parse_retry_after, ApiKeyUsage, RateLimitHeaders, RateLimitProfile,
+};
+use nationsglory_api_proxy::server::Server;
+use nationsglory_api_proxy::shutdown::Shutdown;
+use nationsglory_api_proxy::utils::{QueuedRequest, WaitingRequests};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use wiremock::matchers::{header, method, path, path_regex, query_param, query_param_is_missing};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn queued_request(url: &str, api_keys: &[&str]) -> QueuedRequest {
+    QueuedRequest {
+        route: "test".to_string(),
+        request_id: "test".to_string(),
+        url: url.to_string(),
+        method: "GET".to_string(),
+        api_keys: api_keys.iter().map(|key| key.to_string()).collect(),
+        cache_time: None,
+        stale_time: None,
+        background: false,
+        priority: 0,
+        rejected_status: None,
+    }
+}
+
+#[test]
+fn insert_request_to_queue_merges_api_keys_of_identical_requests() {
+    let mut waiting_requests = WaitingRequests::new();
+    QueuedRequest::insert_request_to_queue(&mut waiting_requests, queued_request("/a", &["k1"]));
+    QueuedRequest::insert_request_to_queue(&mut waiting_requests, queued_request("/b", &["k1"]));
+    QueuedRequest::insert_request_to_queue(
+        &mut waiting_requests,
+        queued_request("/a", &["k1", "k2"]),
+    );
+
+    let keys = waiting_requests.keys();
+    assert_eq!(
+        keys,
+        vec![
+            ("GET".to_string(), "/a".to_string()),
+            ("GET".to_string(), "/b".to_string())
+        ]
+    );
+    let merged = waiting_requests.get_mut(&keys[0]).unwrap();
+    assert_eq!(merged.api_keys, vec!["k1".to_string(), "k2".to_string()]);
+}
+
+#[test]
+fn waiting_requests_are_ordered_by_priority_with_aging() {
+    let mut waiting_requests = WaitingRequests::new();
+    QueuedRequest::insert_request_to_queue(&mut waiting_requests, queued_request("/old", &["k1"]));
+    std::thread::sleep(Duration::from_millis(30));
+    let mut urgent = queued_request("/urgent", &["k1"]);
+    urgent.priority = 1;
+    QueuedRequest::insert_request_to_queue(&mut waiting_requests, urgent);
+
+    let urls = |keys: Vec<(String, String)>| keys.into_iter().map(|(_, url)| url).collect::<Vec<_>>();
+    assert_eq!(urls(waiting_requests.keys_by_priority(Duration::ZERO)), vec!["/urgent", "/old"]);
+    // Après 30ms d'attente, une requête gagnant un niveau toutes les 10ms passe devant
+    assert_eq!(urls(waiting_requests.keys_by_priority(Duration::from_millis(10))), vec!["/old", "/urgent"]);
+}
+
+#[test]
+fn clients_are_served_in_turn() {
+    let mut waiting_requests = WaitingRequests::new();
+    for url in ["/heavy/1", "/heavy/2", "/heavy/3"] {
+        QueuedRequest::insert_request_to_queue(&mut waiting_requests, queued_request(url, &["k1"]));
+    }
+    QueuedRequest::insert_request_to_queue(&mut waiting_requests, queued_request("/light/1", &["k2", "k1"]));
+    QueuedRequest::insert_request_to_queue(&mut waiting_requests, queued_request("/light/2", &["k1", "k2"]));
+
+    let urls = |waiting_requests: &WaitingRequests| {
+        waiting_requests
+            .keys_by_priority(Duration::ZERO)
+            .into_iter()
+            .map(|(_, url)| url)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(urls(&waiting_requests), vec!["/heavy/1", "/light/1", "/heavy/2", "/light/2", "/heavy/3"]);
+
+    // Le client qui vient d'être servi passe après les autres
+    waiting_requests.take_for_execution(&("GET".to_string(), "/heavy/1".to_string()));
+    assert_eq!(urls(&waiting_requests), vec!["/light/1", "/heavy/2", "/light/2", "/heavy/3"]);
+}
+
+#[rocket::async_test]
+async fn concurrent_identical_requests_hit_the_upstream_once() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/playercount"))
+        .respond_with(json_response(json!({"red": 12}), Duration::from_millis(300)))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (first, second) = tokio::join!(
+        get_json(&client, "/playercount", "k1"),
+        get_json(&client, "/playercount", "k2"),
+    );
+
+    assert_eq!(first.1, json!({"red": 12}));
+    assert_eq!(second.1, json!({"red": 12}));
+}
+
+#[rocket::async_test]
+async fn cached_responses_are_served_without_calling_the_upstream() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/country/red/france"))
+        .respond_with(json_response(json!({"name": "france"}), Duration::ZERO))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let first = client
+        .get("/country/red/france")
+        .header(rocket::http::Header::new("Authorization", "k1"))
+        .dispatch()
+        .await;
+    assert_eq!(first.headers().get_one("X-Cache"), Some("MISS"));
+    assert_eq!(first.headers().get_one("Age"), Some("0"));
+    assert!(first.headers().get_one("X-Cache-Expires").is_some());
+    assert_eq!(first.into_json::<serde_json::Value>().await.unwrap(), json!({"name": "france"}));
+
+    let second = client
+        .get("/country/red/france")
+        .header(rocket::http::Header::new("Authorization", "k1"))
+        .dispatch()
+        .await;
+    assert_eq!(second.headers().get_one("X-Cache"), Some("HIT"));
+    assert_eq!(second.headers().get_one("X-Queue-Wait-Ms"), Some("0"));
+    // Le corps est celui de l'API, sans enveloppe
+    assert_eq!(second.into_json::<serde_json::Value>().await.unwrap(), json!({"name": "france"}));
+}
+
+#[rocket::async_test]
+async fn expired_responses_are_served_stale_while_being_refreshed() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/user/alice"))
+        .respond_with(json_response(json!({"version": 1}), Duration::ZERO))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    // Une seule actualisation, malgré plusieurs clients servis avec la réponse expirée
+    Mock::given(method("GET"))
+        .and(path("/user/alice"))
+        .respond_with(json_response(json!({"version": 2}), Duration::from_millis(200)))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let mut config = test_config(&upstream);
+    config.routes.insert(
+        "user".to_string(),
+        RoutePolicy {
+            cache_time: Some(1),
+            stale_time: Some(60),
+            ..RoutePolicy::default()
+        },
+    );
+    let client = start_proxy(config).await;
+    let fetch = || async {
+        let response = client
+            .get("/user/alice")
+            .header(rocket::http::Header::new("Authorization", "k1"))
+            .dispatch()
+            .await;
+        let cache_status = response.headers().get_one("X-Cache").map(str::to_string);
+        (cache_status, response.into_json::<serde_json::Value>().await.unwrap())
+    };
+
+    assert_eq!(fetch().await, (Some("MISS".to_string()), json!({"version": 1})));
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    for _ in 0..3 {
+        assert_eq!(fetch().await, (Some("STALE".to_string()), json!({"version": 1})));
+    }
+
+    let started = Instant::now();
+    let refreshed = loop {
+        let response = fetch().await;
+        if response.1 == json!({"version": 2}) || started.elapsed() > Duration::from_secs(3) {
+            break response;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert_eq!(refreshed, (Some("HIT".to_string()), json!({"version": 2})));
+}
+
+#[rocket::async_test]
+async fn an_explicit_cache_backend_that_cannot_be_created_stops_the_startup() {
+    let upstream = MockServer::start().await;
+    let mut config = test_config(&upstream);
+    config.cache_backend = Some("redis".to_string());
+    config.redis_url = Some("not a redis url".to_string());
+    assert!(build_rocket(config.clone()).is_err());
+    config.cache_backend = Some("memcached".to_string());
+    assert!(build_rocket(config.clone()).is_err());
+
+    // Sans backend explicite, une redis_url invalide fait retomber sur le cache en mémoire
+    config.cache_backend = None;
+    let client = start_proxy(config).await;
+    let (status, body) = get_json(&client, "/readyz", "").await;
+    assert_eq!(status, 200, "{}", body);
+}
+
+#[rocket::async_test]
+async fn notations_can_be_filtered_by_country() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/notations"))
+        .and(query_param("week", "2880"))
+        .and(query_param("server", "red"))
+        .respond_with(json_response(
+            json!([
+                {"pays": "France", "notation": 10},
+                {"pays": "Spain", "notation": 7},
+                {"pays": "france", "notation": 3}
+            ]),
+            Duration::ZERO,
+        ))
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (_, body) = get_json(&client, "/notations?week=2880&server=RED&country=FRANCE", "k1").await;
+
+    assert_eq!(
+        body,
+        json!([
+            {"pays": "France", "notation": 10},
+            {"pays": "france", "notation": 3}
+        ])
+    );
+}
+
+#[rocket::async_test]
+async fn notations_of_every_server_are_fetched_without_a_server() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/notations"))
+        .and(query_param("week", "2880"))
+        .and(query_param_is_missing("server"))
+        .respond_with(json_response(json!([{"pays": "France", "notation": 10}]), Duration::ZERO))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (status, body) = get_json(&client, "/notations?week=2880", "k1").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!([{"pays": "France", "notation": 10}]));
+
+    let (status, body) = get_json(&client, "/notations?week=2880&server=pink", "k1").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], json!("unknown_server"));
+}
+
+#[rocket::async_test]
+async fn requests_are_spread_over_the_available_keys() {
+    let upstream = MockServer::start().await;
+    for api_key in ["k1", "k2"] {
+        Mock::given(method("GET"))
+            .and(header("Authorization", format!("Bearer {}", api_key).as_str()))
+            .respond_with(json_response(json!({"ok": true}), Duration::from_millis(100)))
+            .expect(1)
+            .mount(&upstream)
+            .await;
+    }
+    let mut config = test_config(&upstream);
+    config.api_key_rate_limit = Some("1/0.1".to_string()); // Un jeton toutes les 10 secondes
+    let client = start_proxy(config).await;
+
+    let started = Instant::now();
+    let (first, second) = tokio::join!(
+        get_json(&client, "/user/alice", "k1,k2"),
+        get_json(&client, "/user/bob", "k1,k2"),
+    );
+
+    assert_eq!(first.1, json!({"ok": true}));
+    assert_eq!(second.1, json!({"ok": true}));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[rocket::async_test]
+async fn rejected_keys_fall_back_to_the_remaining_keys() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(header("Authorization", "Bearer revoked"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({"error": "Unauthorized"})))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    Mock::given(method("GET"))
+        .and(header("Authorization", "Bearer valid"))
+        .respond_with(json_response(json!({"name": "alice"}), Duration::ZERO))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (_, body) = get_json(&client, "/user/alice", "revoked,valid").await;
+
+    assert_eq!(body, json!({"name": "alice"}));
+}
+
+#[rocket::async_test]
+async fn a_rejected_key_does_not_answer_other_clients_of_the_same_url() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(header("Authorization", "Bearer bad"))
+        .respond_with(
+            ResponseTemplate::new(401)
+                .set_body_json(json!({"error": "Unauthorized"}))
+                .set_delay(Duration::from_millis(300)),
+        )
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    Mock::given(method("GET"))
+        .and(header("Authorization", "Bearer good"))
+        .respond_with(json_response(json!({"name": "alice"}), Duration::ZERO))
+        .mount(&upstream)
+        .await;
+    let mut config = test_config(&upstream);
+    config.api_key_profiles.insert("good".to_string(), "1/1".to_string());
+    let client = start_proxy(config).await;
+    // La clé "good" n'a plus de jeton : la requête du second client attend dans la file pendant l'appel avec "bad"
+    get_json(&client, "/user/bob", "good").await;
+
+    let (first, second) = tokio::join!(get_json(&client, "/user/alice", "bad"), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        get_json(&client, "/user/alice", "good").await
+    });
+
+    // La requête refusée est fusionnée avec celle du second client : tous deux reçoivent sa réponse
+    assert_eq!(second, (200, json!({"name": "alice"})));
+    assert_eq!(first, (200, json!({"name": "alice"})));
+}
+
+#[rocket::async_test]
+async fn an_error_is_returned_once_every_key_was_rejected() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(403).set_body_json(json!({"error": "Forbidden"})))
+        .expect(2)
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (status, body) = get_json(&client, "/user/alice", "k1,k2").await;
+
+    assert_eq!(status, 401);
+    assert_eq!(body["code"], json!("invalid_api_key"));
+    assert_eq!(body["upstream_status"], json!(403));
+}
+
+#[rocket::async_test]
+async fn rate_limited_requests_are_retried_after_the_delay() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    mount_json(&upstream, "/playercount", json!({"red": 12})).await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let started = Instant::now();
+    let (_, body) = get_json(&client, "/playercount", "k1").await;
+
+    assert_eq!(body, json!({"red": 12}));
+    assert!(started.elapsed() >= Duration::from_millis(900));
+}
+
+#[rocket::async_test]
+async fn an_exhausted_quota_delays_the_next_call_until_the_reset() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/user/alice"))
+        .respond_with(
+            json_response(json!({"name": "alice"}), Duration::ZERO)
+                .insert_header("X-RateLimit-Limit", "10")
+                .insert_header("X-RateLimit-Remaining", "0")
+                .insert_header("X-RateLimit-Reset", "1"),
+        )
+        .mount(&upstream)
+        .await;
+    mount_json(&upstream, "/user/bob", json!({"name": "bob"})).await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let started = Instant::now();
+    get_json(&client, "/user/alice", "k1").await;
+    assert!(started.elapsed() < Duration::from_millis(500));
+    // L'API annonce un solde nul jusqu'au reset : la clé attend avant d'être réutilisée
+    let (_, body) = get_json(&client, "/user/bob", "k1").await;
+
+    assert_eq!(body, json!({"name": "bob"}));
+    assert!(started.elapsed() >= Duration::from_millis(900));
+}
+
+#[test]
+fn rate_limit_delays_are_read_as_seconds_or_dates() {
+    let now = Utc::now();
+    assert_eq!(parse_retry_after("5"), Some(Duration::from_secs(5)));
+    let date = (now + chrono::Duration::seconds(120)).to_rfc2822();
+    let delay = parse_retry_after(&date).unwrap();
+    assert!(delay > Duration::from_secs(110) && delay <= Duration::from_secs(120));
+    let past = (now - chrono::Duration::seconds(120)).to_rfc2822();
+    assert_eq!(parse_retry_after(&past), Some(Duration::ZERO));
+    assert_eq!(parse_retry_after("soon"), None);
+
+    assert_eq!(parse_reset(30), Duration::from_secs(30));
+    let delay = parse_reset(now.timestamp() as u64 + 30);
+    assert!(delay > Duration::from_secs(25) && delay <= Duration::from_secs(30));
+
+    // Les valeurs aberrantes sont bornées au lieu de faire déborder les dates
+    let day = Duration::from_secs(60 * 60 * 24);
+    assert_eq!(parse_retry_after("18446744073709551615"), Some(day));
+    assert_eq!(parse_reset(u64::MAX), day);
+}
+
+#[test]
+fn rate_limit_profiles_are_parsed_and_validated() {
+    assert_eq!(
+        RateLimitProfile::parse(" 10 / 2 "),
+        Some(RateLimitProfile {
+            capacity: 10.0,
+            refill_per_second: 2.0,
+        })
+    );
+    assert_eq!(RateLimitProfile::parse("1/0.5").map(|profile| profile.refill_per_second), Some(0.5));
+    for invalid in ["10", "0/2", "0.5/2", "10/0", "10/-1", "a/b", ""] {
+        assert_eq!(RateLimitProfile::parse(invalid), None, "{:?}", invalid);
+    }
+}
+
+#[test]
+fn key_profiles_are_learned_from_the_headers_unless_configured() {
+    let usage = ApiKeyUsage::new(RateLimitProfile::default());
+    usage.set_profile("manual".to_string(), RateLimitProfile::parse("1/2").unwrap());
+    // 10 requêtes par fenêtre de 10 secondes : un jeton par seconde
+    let headers = RateLimitHeaders {
+        limit: Some(10),
+        reset_after: Some(Duration::from_secs(10)),
+        ..RateLimitHeaders::default()
+    };
+    for api_key in ["learned", "manual"] {
+        usage.update_budget(api_key, &headers);
+        usage.update_usage(api_key.to_string());
+    }
+    let wait = |api_key: &str| {
+        usage
+            .next_available(&api_key.to_string())
+            .saturating_duration_since(Instant::now())
+    };
+
+    let learned = wait("learned");
+    assert!(learned > Duration::from_millis(800) && learned <= Duration::from_secs(1), "{:?}", learned);
+    // Le profil configuré (deux jetons par seconde) n'est pas remplacé par celui annoncé par l'API
+    let manual = wait("manual");
+    assert!(manual > Duration::from_millis(300) && manual <= Duration::from_millis(500), "{:?}", manual);
+}
+
+#[rocket::async_test]
+async fn malformed_upstream_json_is_reported_and_not_cached() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/hdv/red/list"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("{not json"))
+        .expect(2)
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (first_status, first) = get_json(&client, "/hdv/red/list", "k1").await;
+    let (second_status, _) = get_json(&client, "/hdv/red/list", "k1").await;
+
+    assert_eq!(first_status, 502);
+    assert_eq!(
+        first,
+        json!({
+            "error": "Failed to parse response",
+            "code": "invalid_upstream_response",
+            "upstream_status": 200
+        })
+    );
+    assert_eq!(second_status, 502);
+}
+
+#[rocket::async_test]
+async fn requests_without_api_key_get_a_json_error() {
+    let upstream = MockServer::start().await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let response = client.get("/playercount").dispatch().await;
+
+    assert_eq!(response.status().code, 400);
+    let body = response.into_json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["code"], json!("missing_api_key"));
+    assert_eq!(body["upstream_status"], json!(null));
+}
+
+#[rocket::async_test]
+async fn unreachable_upstream_is_reported_as_bad_gateway() {
+    let upstream = MockServer::start().await;
+    let mut config = test_config(&upstream);
+    config.upstream_base_url = "http://127.0.0.1:9".to_string();
+    let client = start_proxy(config).await;
+
+    let (status, body) = get_json(&client, "/playercount", "k1").await;
+
+    assert_eq!(status, 502);
+    assert_eq!(body["code"], json!("upstream_unavailable"));
+}
+
+#[rocket::async_test]
+async fn notations_without_country_field_do_not_break_the_filter() {
+    let upstream = MockServer::start().await;
+    mount_json(
+        &upstream,
+        "/notations",
+        json!([{"notation": 10}, {"pays": null}, {"pays": "France"}]),
+    )
+    .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (status, body) = get_json(&client, "/notations?week=2880&server=red&country=france", "k1").await;
+
+    assert_eq!(status, 200);
+    assert_eq!(body, json!([{"pays": "France"}]));
+}
+
+#[rocket::async_test]
+async fn upstream_not_found_is_propagated_and_not_cached() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/user/unknown"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({"message": "User not found"})))
+        .expect(2)
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (first_status, first) = get_json(&client, "/user/unknown", "k1").await;
+    let (second_status, _) = get_json(&client, "/user/unknown", "k1").await;
+
+    assert_eq!(first_status, 404);
+    assert_eq!(second_status, 404);
+    assert_eq!(first, json!({"message": "User not found"}));
+}
+
+#[rocket::async_test]
+async fn selected_upstream_headers_are_forwarded() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/playercount"))
+        .respond_with(
+            json_response(json!({"red": 12}), Duration::ZERO)
+                .insert_header("ETag", "\"abc\"")
+                .insert_header("X-Internal", "secret"),
+        )
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let response = client
+        .get("/playercount")
+        .header(rocket::http::Header::new("Authorization", "k1"))
+        .dispatch()
+        .await;
+
+    assert_eq!(response.status().code, 200);
+    assert_eq!(response.headers().get_one("etag"), Some("\"abc\""));
+    assert_eq!(response.headers().get_one("x-internal"), None);
+}
+
+#[rocket::async_test]
+async fn slow_requests_time_out_with_gateway_timeout() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/playercount"))
+        .respond_with(json_response(json!({"red": 12}), Duration::from_secs(3)))
+        .mount(&upstream)
+        .await;
+    let mut config = test_config(&upstream);
+    config.request_timeout = 1;
+    let client = start_proxy(config).await;
+
+    let (status, body) = get_json(&client, "/playercount", "k1").await;
+
+    assert_eq!(status, 504);
+    assert_eq!(body["code"], json!("timeout"));
+}
+
+#[rocket::async_test]
+async fn abandoned_upstream_calls_release_their_key() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/playercount"))
+        .respond_with(json_response(json!({"red": 12}), Duration::from_secs(3)))
+        .mount(&upstream)
+        .await;
+    mount_json(&upstream, "/user/notch", json!({"username": "notch"})).await;
+    let mut config = test_config(&upstream);
+    config.request_timeout = 1;
+    let client = start_proxy(config).await;
+
+    let (status, _) = get_json(&client, "/playercount", "k1").await;
+    assert_eq!(status, 504);
+
+    // Sans annulation, la clé resterait occupée jusqu'à la réponse de l'API (3s)
+    let started = Instant::now();
+    let (status, _) = get_json(&client, "/user/notch", "k1").await;
+    assert_eq!(status, 200);
+    assert!(started.elapsed() < Duration::from_millis(800));
+}
+
+#[rocket::async_test]
+async fn queued_requests_nobody_waits_for_are_not_sent() {
+    let upstream = MockServer::start().await;
+    mount_json(&upstream, "/playercount", json!({"red": 12})).await;
+    Mock::given(method("GET"))
+        .and(path("/user/notch"))
+        .respond_with(json_response(json!({"username": "notch"}), Duration::ZERO))
+        .expect(0)
+        .mount(&upstream)
+        .await;
+    let mut config = test_config(&upstream);
+    config.request_timeout = 1;
+    config.api_key_rate_limit = Some("1/0.5".to_string()); // Un jeton toutes les 2 secondes
+    let client = start_proxy(config).await;
+
+    let (status, _) = get_json(&client, "/playercount", "k1").await;
+    assert_eq!(status, 200);
+    let (status, _) = get_json(&client, "/user/notch", "k1").await;
+    assert_eq!(status, 504);
+
+    // On laisse à la clé le temps de regagner un jeton : la requête abandonnée ne doit pas être envoyée
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+}
+
+#[rocket::async_test]
+async fn each_client_receives_its_own_response_under_load() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/user/"))
+        .respond_with(|request: &wiremock::Request| {
+            let username = request.url.path().trim_start_matches("/user/").to_string();
+            ResponseTemplate::new(200).set_body_json(json!({ "username": username }))
+        })
+        .mount(&upstream)
+        .await;
+    let mut config = test_config(&upstream);
+    config.max_queued_per_client = 0; // Toutes les requêtes viennent du même client
+    let client = start_proxy(config).await;
+
+    // Bien plus de réponses que l'ancien canal de diffusion (100) ne pouvait en contenir
+    let usernames: Vec<String> = (0..250).map(|index| format!("player{}", index)).collect();
+    let uris: Vec<String> = usernames.iter().map(|username| format!("/user/{}", username)).collect();
+    let responses = rocket::futures::future::join_all(
+        uris.iter().map(|uri| get_json(&client, uri, "k1,k2,k3")),
+    )
+    .await;
+
+    for (username, (status, body)) in usernames.iter().zip(responses) {
+        assert_eq!(status, 200);
+        assert_eq!(body["username"], json!(username));
+    }
+}
+
+#[rocket::async_test]
+async fn metrics_report_cache_upstream_and_key_usage() {
+    let upstream = MockServer::start().await;
+    mount_json(&upstream, "/country/red/france", json!({"name": "france"})).await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    get_json(&client, "/country/red/france", "secret-key").await;
+    get_json(&client, "/country/red/france", "secret-key").await;
+
+    let response = client.get("/metrics").dispatch().await;
+    assert_eq!(response.status().code, 200);
+    let metrics = response.into_string().await.unwrap();
+
+    assert!(metrics.contains("proxy_cache_requests_total{outcome=\"hit\"} 1"));
+    assert!(metrics.contains("proxy_cache_requests_total{outcome=\"miss\"} 1"));
+    assert!(metrics.contains("proxy_upstream_responses_total{route=\"country\",status=\"200\"} 1"));
+    assert!(metrics.contains("proxy_upstream_request_duration_seconds_count{route=\"country\"} 1"));
+    assert!(metrics.contains("proxy_api_keys_seen 1"));
+    assert!(metrics.contains("proxy_queue_depth 0"));
+    // Les clés API n'apparaissent que sous forme de hash
+    assert!(!metrics.contains("secret-key"));
+    assert!(metrics.contains("proxy_api_key_requests_total{key="));
+}
+
+#[test]
+fn invalid_and_idle_keys_are_not_exported() {
+    let metrics = Metrics::new();
+    let usage = ApiKeyUsage::new(RateLimitProfile::default());
+    for api_key in ["active", "refused", "idle"] {
+        usage.update_usage(api_key.to_string());
+    }
+    let series = |api_key: &str| format!("proxy_api_key_requests_total{{key=\"{}\"}}", key_id(api_key));
+    let rendered = metrics.render(&usage);
+    assert!(rendered.contains(&series("idle")));
+    assert!(rendered.contains(&series("refused")));
+
+    let cutoff = SystemTime::now();
+    usage.update_usage("active".to_string());
+    usage.mark_invalid("refused".to_string());
+    usage.forget_keys_unused_since(cutoff);
+    let rendered = metrics.render(&usage);
+    assert!(rendered.contains(&format!("{} 2", series("active"))));
+    // La clé refusée est conservée (encore en pause) mais n'est plus exportée ; la clé inactive est oubliée
+    assert!(!rendered.contains(&series("refused")));
+    assert!(!rendered.contains(&series("idle")));
+    assert_eq!(usage.stats().len(), 2);
+    assert!(rendered.contains("proxy_api_keys_seen 2"));
+}
+
+#[test]
+fn models_keep_unknown_fields() {
+    let payload = json!({
+        "username": "notch",
+        "skills": [{"name": "mineur", "level": 12, "xp": 300}],
+        "rank": "admin"
+    });
+    let user: User = serde_json::from_value(payload.clone()).unwrap();
+
+    assert_eq!(user.username.as_deref(), Some("notch"));
+    assert_eq!(user.skills[0].level, Some(12.0));
+    assert_eq!(user.extra["rank"], json!("admin"));
+    assert_eq!(user.skills[0].extra["xp"], json!(300));
+    // Les champs absents ne sont pas une erreur
+    assert_eq!(serde_json::from_value::<User>(json!({})).unwrap(), User::default());
+}
+
+#[rocket::async_test]
+async fn schema_drift_is_reported_without_breaking_the_response() {
+    let upstream = MockServer::start().await;
+    mount_json(&upstream, "/playercount", json!({"red": "twelve"})).await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (status, body) = get_json(&client, "/playercount", "k1").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({"red": "twelve"}));
+    // La réponse en cache n'est pas comptée une seconde fois
+    get_json(&client, "/playercount", "k1").await;
+
+    let metrics = client.get("/metrics").dispatch().await.into_string().await.unwrap();
+    assert!(metrics.contains("proxy_upstream_schema_mismatches_total{route=\"playercount\"} 1"));
+}
+
+#[rocket::async_test]
+async fn readiness_reports_every_check() {
+    let upstream = MockServer::start().await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let response = client.get("/healthz").dispatch().await;
+    assert_eq!(response.status().code, 200);
+
+    let (status, body) = get_json(&client, "/readyz", "").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], json!("ok"));
+    for check in ["cache", "worker", "queue", "upstream"] {
+        assert_eq!(body["checks"][check]["status"], json!("ok"), "{}", check);
+    }
+}
+
+#[rocket::async_test]
+async fn readiness_is_degraded_when_the_cache_or_upstream_fail() {
+    let upstream = MockServer::start().await;
+    let mut config = test_config(&upstream);
+    config.cache_backend = Some("redis".to_string());
+    config.redis_url = Some("redis://127.0.0.1:1/".to_string());
+    config.upstream_success_max_age = Some(60);
+    let client = start_proxy(config).await;
+
+    let (status, body) = get_json(&client, "/readyz", "").await;
+
+    assert_eq!(status, 503);
+    assert_eq!(body["status"], json!("degraded"));
+    assert_eq!(body["checks"]["cache"]["status"], json!("error"));
+    assert_eq!(body["checks"]["upstream"]["status"], json!("error"));
+    assert_eq!(body["checks"]["worker"]["status"], json!("ok"));
+}
+
+#[rocket::async_test]
+async fn responses_carry_a_request_id() {
+    let upstream = MockServer::start().await;
+    mount_json(&upstream, "/playercount", json!({"red": 12})).await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let response = client
+        .get("/playercount")
+        .header(rocket::http::Header::new("Authorization", "k1"))
+        .dispatch()
+        .await;
+    let generated = response.headers().get_one("X-Request-Id").map(str::to_string);
+    assert!(generated.is_some_and(|id| !id.is_empty()));
+
+    // Un identifiant fourni par le client est repris, y compris sur les erreurs
+    let response = client
+        .get("/playercount")
+        .header(rocket::http::Header::new("X-Request-Id", "abc-123"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status().code, 400);
+    assert_eq!(response.headers().get_one("X-Request-Id"), Some("abc-123"));
+}
+
+fn trigger_shutdown(client: &rocket::local::asynchronous::Client) {
+    client
+        .rocket()
+        .state::<std::sync::Arc<Shutdown>>()
+        .expect("managed shutdown")
+        .trigger();
+}
+
+#[rocket::async_test]
+async fn shutdown_answers_queued_requests_with_retry_after() {
+    let upstream = MockServer::start().await;
+    mount_json(&upstream, "/playercount", json!({"red": 12})).await;
+    mount_json(&upstream, "/user/notch", json!({"username": "notch"})).await;
+    let mut config = test_config(&upstream);
+    config.api_key_rate_limit = Some("1/0.2".to_string()); // Un jeton toutes les 5 secondes
+    config.shutdown_retry_after = 42;
+    config.persist_queue = true;
+    let client = start_proxy(config).await;
+
+    get_json(&client, "/playercount", "k1").await;
+    let queued = async {
+        client
+            .get("/user/notch")
+            .header(rocket::http::Header::new("Authorization", "k1"))
+            .dispatch()
+            .await
+    };
+    let shutdown = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        trigger_shutdown(&client);
+    };
+    let (response, _) = tokio::join!(queued, shutdown);
+
+    assert_eq!(response.status().code, 503);
+    assert_eq!(response.headers().get_one("Retry-After"), Some("42"));
+    let body = response.into_json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["code"], json!("shutting_down"));
+
+    // La file d'attente a été sauvegardée pour être reprise au redémarrage
+    let cache = client.rocket().state::<Cache>().expect("managed cache");
+    let persisted = cache.get("queue:pending").await.unwrap().unwrap();
+    assert!(persisted.contains("/user/notch"));
+
+    // Les nouvelles requêtes sont refusées, et /readyz signale l'arrêt
+    let (status, _) = get_json(&client, "/country/red/france", "k1").await;
+    assert_eq!(status, 503);
+    let (status, body) = get_json(&client, "/readyz", "").await;
+    assert_eq!(status, 503);
+    assert_eq!(body["status"], json!("shutting_down"));
+}
+
+#[rocket::async_test]
+async fn shutdown_lets_in_flight_calls_finish_and_fill_the_cache() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/playercount"))
+        .respond_with(json_response(json!({"red": 12}), Duration::from_millis(500)))
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let in_flight = get_json(&client, "/playercount", "k1");
+    let shutdown = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        trigger_shutdown(&client);
+    };
+    let ((status, body), _) = tokio::join!(in_flight, shutdown);
+
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({"red": 12}));
+    let cache = client.rocket().state::<Cache>().expect("managed cache");
+    let cache_key = format!("cache:{}/playercount", upstream.uri());
+    assert!(cache.get(&cache_key).await.unwrap().is_some());
+}
+
+#[rocket::async_test]
+async fn higher_priority_requests_are_served_first() {
+    let upstream = MockServer::start().await;
+    mount_json(&upstream, "/playercount", json!({"red": 12})).await;
+    mount_json(&upstream, "/user/notch", json!({"username": "notch"})).await;
+    mount_json(&upstream, "/ngisland/list", json!({"data": []})).await;
+    let mut config = test_config(&upstream);
+    config.api_key_rate_limit = Some("1/2".to_string()); // Un jeton toutes les 0.5 secondes
+    config.max_priority = 2;
+    config.routes.insert(
+        "user".to_string(),
+        RoutePolicy {
+            priority: Some(5),
+            ..RoutePolicy::default()
+        },
+    );
+    let client = start_proxy(config).await;
+
+    // La seule clé est épuisée : les requêtes suivantes attendent dans la file
+    get_json(&client, "/playercount", "k1").await;
+    let low_priority = |page: u32| {
+        client
+            .get(format!("/ngisland/list?page={}", page))
+            .header(rocket::http::Header::new("Authorization", "k1"))
+            .header(rocket::http::Header::new("X-Priority", "10")) // Ramenée à max_priority
+            .dispatch()
+    };
+    let high_priority = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        get_json(&client, "/user/notch", "k1").await
+    };
+    let (first, second, user) = tokio::join!(low_priority(1), low_priority(2), high_priority);
+    assert_eq!(first.status().code, 200);
+    assert_eq!(second.status().code, 200);
+    assert_eq!(user.0, 200);
+
+    let received = upstream.received_requests().await.unwrap();
+    let paths: Vec<&str> = received.iter().map(|request| request.url.path()).collect();
+    assert_eq!(paths[..2], ["/playercount", "/user/notch"]);
+}
+
+#[rocket::async_test]
+async fn full_queues_are_answered_with_too_many_requests() {
+    let upstream = MockServer::start().await;
+    mount_json(&upstream, "/playercount", json!({"red": 12})).await;
+    mount_json(&upstream, "/user/notch", json!({"username": "notch"})).await;
+    let mut config = test_config(&upstream);
+    config.api_key_rate_limit = Some("1/0.2".to_string()); // Un jeton toutes les 5 secondes
+    config.request_timeout = 1;
+    config.max_queued_requests = 1;
+    config.max_queued_per_client = 1;
+    let client = start_proxy(config).await;
+
+    get_json(&client, "/playercount", "k1").await;
+    let queued = get_json(&client, "/user/notch", "k1");
+    let rejected = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let per_client = client
+            .get("/user/jeb")
+            .header(rocket::http::Header::new("Authorization", "k1"))
+            .dispatch()
+            .await;
+        let total = client
+            .get("/user/dinnerbone")
+            .header(rocket::http::Header::new("Authorization", "k1,k2"))
+            .dispatch()
+            .await;
+        (per_client, total)
+    };
+    let ((status, _), (per_client, total)) = tokio::join!(queued, rejected);
+    assert_eq!(status, 504);
+
+    // Le client doit attendre que sa clé regagne un jeton
+    assert_eq!(per_client.status().code, 429);
+    let retry_after: u64 = per_client.headers().get_one("Retry-After").unwrap().parse().unwrap();
+    assert!((4..=5).contains(&retry_after));
+    let body = per_client.into_json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["code"], json!("queue_full"));
+    // La file est pleine, même si la clé k2 est libre
+    assert_eq!(total.status().code, 429);
+    assert_eq!(total.headers().get_one("Retry-After"), Some("1"));
+
+    // Les places sont libérées une fois les requêtes terminées
+    let (status, body) = get_json(&client, "/user/notch", "k2").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({"username": "notch"}));
+}
+
+#[rocket::async_test]
+async fn responses_report_coalescing_queue_wait_and_key_budget() {
+    let upstream = MockServer::start().await;
+    mount_json(&upstream, "/playercount", json!({"red": 12})).await;
+    Mock::given(method("GET"))
+        .and(path("/user/notch"))
+        .respond_with(json_response(json!({"username": "notch"}), Duration::from_millis(100)))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let mut config = test_config(&upstream);
+    config.api_key_rate_limit = Some("1/1".to_string()); // Un jeton par seconde
+    let client = start_proxy(config).await;
+
+    // La clé est épuisée : les deux requêtes attendent ensemble le prochain jeton
+    get_json(&client, "/playercount", "k1").await;
+    let request = || {
+        client
+            .get("/user/notch")
+            .header(rocket::http::Header::new("Authorization", "k1"))
+            .dispatch()
+    };
+    let (first, second) = tokio::join!(request(), request());
+
+    let mut cache_statuses: Vec<&str> = [&first, &second]
+        .iter()
+        .map(|response| response.headers().get_one("X-Cache").unwrap())
+        .collect();
+    cache_statuses.sort();
+    assert_eq!(cache_statuses, vec!["COALESCED", "MISS"]);
+    for response in [&first, &second] {
+        let queue_wait: u64 = response.headers().get_one("X-Queue-Wait-Ms").unwrap().parse().unwrap();
+        assert!((500..1500).contains(&queue_wait));
+        assert_eq!(response.headers().get_one("X-RateLimit-Remaining"), Some("0"));
+    }
+}
+
+#[rocket::async_test]
+async fn unknown_servers_are_rejected_without_calling_the_upstream() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(json_response(json!({"error": "Unknown server"}), Duration::ZERO))
+        .expect(0)
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    for uri in [
+        "/hdv/rde/list",
+        "/country/list/rde",
+        "/country/rde/france",
+        "/planning?server=rde&month=06&year=2024",
+        "/notations?week=2880&server=rde",
+    ] {
+        let (status, body) = get_json(&client, uri, "k1").await;
+        assert_eq!(status, 400, "{}", uri);
+        assert_eq!(body["code"], json!("unknown_server"), "{}", uri);
+    }
+}
+
+#[rocket::async_test]
+async fn servers_are_listed_with_their_player_counts() {
+    let upstream = MockServer::start().await;
+    mount_json(&upstream, "/playercount", json!({"red": 12, "Blue": 30})).await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (status, body) = get_json(&client, "/servers", "k1").await;
+
+    assert_eq!(status, 200);
+    let servers = body.as_array().unwrap();
+    assert!(servers.contains(&json!({"name": "red", "player_count": 12})));
+    assert!(servers.contains(&json!({"name": "blue", "player_count": 30})));
+    assert!(servers.contains(&json!({"name": "coral", "player_count": null})));
+}
+
+#[rocket::async_test]
+async fn servers_forward_upstream_errors_unchanged() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/playercount"))
+        .respond_with(ResponseTemplate::new(503).set_body_json(json!({"error": "Maintenance"})))
+        .mount(&upstream)
+        .await;
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (status, body) = get_json(&client, "/servers", "k1").await;
+
+    assert_eq!(status, 503);
+    assert_eq!(body, json!({"error": "Maintenance"}));
+}
+
+#[rocket::async_test]
+async fn hdv_snapshots_are_collected_into_the_history() {
+    let upstream = MockServer::start().await;
+    mount_json(
+        &upstream,
+        "/hdv/red/list",
+        json!([
+            {"item": "Dirt", "price": 10, "quantity": 64, "seller": "notch"},
+            {"item": "dirt ", "price": 20},
+            {"item": "stone", "price": 5},
+            {"price": 1}
+        ]),
+    )
+    .await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/hdv/"))
+        .respond_with(json_response(json!([]), Duration::ZERO))
+        .mount(&upstream)
+        .await;
+    let mut config = test_config(&upstream);
+    config.collector_api_keys = vec!["collector".to_string()];
+    let client = start_proxy(config).await;
+
+    // Le premier relevé est lancé au démarrage
+    let started = Instant::now();
+    let volume = loop {
+        let (_, body) = get_json(&client, "/hdv/red/volume", "").await;
+        if !body["points"].as_array().unwrap().is_empty() || started.elapsed() > Duration::from_secs(5) {
+            break body;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert_eq!(volume["points"][0]["listings"], json!(3));
+
+    let (status, body) = get_json(&client, "/hdv/RED/history?item=DIRT", "").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["item"], json!("dirt"));
+    let point = &body["points"][0];
+    assert_eq!((point["listings"].clone(), point["quantity"].clone()), (json!(2), json!(65)));
+    assert_eq!((point["min_price"].clone(), point["max_price"].clone()), (json!(10.0), json!(20.0)));
+}
+
+#[rocket::async_test]
+async fn hdv_price_statistics_cover_the_requested_window() {
+    let upstream = MockServer::start().await;
+    let client = start_proxy(test_config(&upstream)).await;
+    let history = client.rocket().state::<Arc<History>>().expect("managed history");
+    let listing = |price: f64| NormalizedListing {
+        item: "dirt".to_string(),
+        price,
+        quantity: 1,
+        seller: None,
+    };
+    let day = |day: u32| Utc.with_ymd_and_hms(2024, 6, day, 12, 0, 0).unwrap();
+    history.record_hdv(Server::Red, day(1), &[listing(100.0)]).unwrap();
+    history.record_hdv(Server::Red, day(2), &[listing(10.0), listing(30.0)]).unwrap();
+    history.record_hdv(Server::Red, day(3), &[listing(20.0), listing(40.0)]).unwrap();
+    history.record_hdv(Server::Blue, day(2), &[listing(1.0)]).unwrap();
+    // Un relevé déjà enregistré (réponse venant du cache) n'est pas dupliqué
+    assert!(!history.record_hdv(Server::Red, day(3), &[listing(20.0)]).unwrap());
+
+    let (status, body) = get_json(&client, "/hdv/red/stats?item=dirt&from=2024-06-02&to=2024-06-04", "").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["listings"], json!(4));
+    assert_eq!((body["min"].clone(), body["median"].clone(), body["max"].clone()), (json!(10.0), json!(25.0), json!(40.0)));
+
+    let (_, body) = get_json(&client, "/hdv/red/volume?from=2024-06-01&to=2024-06-30", "").await;
+    let listings: Vec<&serde_json::Value> = body["points"].as_array().unwrap().iter().map(|point| &point["listings"]).collect();
+    assert_eq!(listings, vec![&json!(1), &json!(2), &json!(2)]);
+
+    let (status, body) = get_json(&client, "/hdv/red/stats?item=dirt&from=yesterday", "").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], json!("invalid_parameter"));
+}
+
+#[rocket::async_test]
+async fn player_counts_are_sampled_into_the_history() {
+    let upstream = MockServer::start().await;
+    mount_json(&upstream, "/playercount", json!({"Red": 12, "blue": 30, "unknown": 3})).await;
+    let mut config = test_config(&upstream);
+    config.collector_api_keys = vec!["collector".to_string()];
+    let client = start_proxy(config).await;
+
+    // Le premier relevé est lancé au démarrage
+    let started = Instant::now();
+    let body = loop {
+        let (_, body) = get_json(&client, "/playercount/history?server=red&resolution=raw", "").await;
+        if !body["points"].as_array().unwrap().is_empty() || started.elapsed() > Duration::from_secs(5) {
+            break body;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert_eq!(body["resolution"], json!("raw"));
+    assert_eq!(body["points"][0]["peak"], json!(12));
+    let (_, body) = get_json(&client, "/playercount/history?server=blue&resolution=raw", "").await;
+    assert_eq!(body["points"][0]["peak"], json!(30));
+}
+
+#[rocket::async_test]
+async fn player_count_history_is_aggregated_by_resolution_day_and_hour_of_week() {
+    let upstream = MockServer::start().await;
+    let client = start_proxy(test_config(&upstream)).await;
+    let history = client.rocket().state::<Arc<History>>().expect("managed history");
+    // Le 3 juin 2024 est un lundi
+    let time = |day: u32, hour: u32, minute: u32| Utc.with_ymd_and_hms(2024, 6, day, hour, minute, 0).unwrap();
+    history.record_player_counts(time(3, 10, 0), &[(Server::Red, 10), (Server::Blue, 100)]).unwrap();
+    history.record_player_counts(time(3, 10, 30), &[(Server::Red, 20)]).unwrap();
+    history.record_player_counts(time(3, 11, 0), &[(Server::Red, 30)]).unwrap();
+    history.record_player_counts(time(4, 10, 15), &[(Server::Red, 40)]).unwrap();
+    // Un relevé déjà enregistré n'est pas dupliqué
+    assert_eq!(history.record_player_counts(time(4, 10, 15), &[(Server::Red, 50)]).unwrap(), 0);
+
+    let query = "/playercount/history?server=red&from=2024-06-01&to=2024-06-30";
+    let (status, body) = get_json(&client, query, "").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["resolution"], json!("hour"));
+    let points = body["points"].as_array().unwrap();
+    assert_eq!(points.len(), 3);
+    assert_eq!(points[0]["time"], json!("2024-06-03T10:00:00Z"));
+    assert_eq!((points[0]["peak"].clone(), points[0]["avg"].clone()), (json!(20), json!(15.0)));
+    assert_eq!(body["daily"][0], json!({"date": "2024-06-03", "peak": 30, "avg": 20.0, "samples": 3}));
+    assert_eq!(body["daily"][1]["peak"], json!(40));
+    let hours: Vec<(String, u64)> = body["hour_of_week"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hour| (hour["weekday"].as_str().unwrap().to_string(), hour["hour"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(hours, vec![("mon".to_string(), 10), ("mon".to_string(), 11), ("tue".to_string(), 10)]);
+
+    let (_, body) = get_json(&client, &format!("{}&resolution=day", query), "").await;
+    assert_eq!(body["points"].as_array().unwrap().len(), 2);
+    let (_, body) = get_json(&client, &format!("{}&resolution=raw", query), "").await;
+    assert_eq!(body["points"].as_array().unwrap().len(), 4);
+
+    let (status, body) = get_json(&client, &format!("{}&resolution=week", query), "").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], json!("invalid_parameter"));
+    let (status, _) = get_json(&client, "/playercount/history?server=pink", "").await;
+    assert_eq!(status, 400);
+}
+
+#[rocket::async_test]
+async fn notations_are_ranked_over_a_range_of_weeks() {
+    let upstream = MockServer::start().await;
+    for (week, notations) in [
+        (
+            "2880",
+            json!([
+                {"pays": "France", "notation": 10},
+                {"pays": "france", "notation": 3},
+                {"pays": "Spain", "notation": 7},
+                {"pays": "Italy", "notation": 13}
+            ]),
+        ),
+        (
+            "2881",
+            json!([
+                {"pays": "Spain", "notation": 20},
+                {"pays": "France", "notation": 5},
+                {"pays": "Italy", "notation": 1}
+            ]),
+        ),
+    ] {
+        // Les semaines passées sont gardées en cache : une seule requête par semaine malgré les deux appels
+        Mock::given(method("GET"))
+            .and(path("/notations"))
+            .and(query_param("week", week))
+            .and(query_param("server", "red"))
+            .respond_with(json_response(notations, Duration::ZERO))
+            .expect(1)
+            .mount(&upstream)
+            .await;
+    }
+    let client = start_proxy(test_config(&upstream)).await;
+
+    let (status, body) = get_json(&client, "/notations/range?server=red&from_week=2880&to_week=2881", "k1").await;
+    assert_eq!(status, 200);
+    let summary: Vec<(String, u64, f64, f64, i64)> = body["countries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|country| {
+            (
+                country["country"].as_str().unwrap().to_string(),
+                country["rank"].as_u64().unwrap(),
+                country["total"].as_f64().unwrap(),
+                country["average"].as_f64().unwrap(),
+                country["rank_movement"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("Spain".to_string(), 1, 27.0, 13.5, 2),
+            ("France".to_string(), 2, 18.0, 9.0, -1),
+            ("Italy".to_string(), 3, 14.0, 7.0, -2),
+        ]
+    );
+    // Les ex æquo partagent le même rang
+    assert_eq!(
+        body["countries"][1]["weeks"],
+        json!([{"week": 2880, "notation": 13.0, "rank": 1}, {"week": 2881, "notation": 5.0, "rank": 2}])
+    );
+
+    let (_, body) = get_json(&client, "/notations/range?server=red&from_week=2880&to_week=2881&country=SPAIN", "k1").await;
+    assert_eq!(body["countries"].as_array().unwrap().len(), 1);
+    assert_eq!(body["countries"][0]["rank"], json!(1));
+
+    let (status, body) = get_json(&client, "/notations/range?server=red&from_week=2881&to_week=2880", "k1").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], json!("invalid_parameter"));
+}
+
+#[rocket::async_test]
+async fn notation_ranges_stay_within_the_client_queue_limit() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/notations"))
+        .respond_with(json_response(json!([{"pays": "France", "notation": 1}]), Duration::from_millis(20)))
+        .expect(6)
+        .mount(&upstream)
+        .await;
+    let mut config = test_config(&upstream);
+    config.max_queued_per_client = 2;
+    let client = start_proxy(config).await;
+
+    // Six semaines pour un client limité à deux requêtes en attente : elles sont demandées deux par deux
+    let (status, body) = get_json(&client, "/notations/range?server=red&from_week=2870&to_week=2875", "k1").await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["countries"][0]["total"], json!(6.0));
+
+    let (status, body) = get_json(&client, "/notations/range?server=red&from_week=2800&to_week=2860", "k1").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], json!("range_too_large"));
+
+    // Des semaines extrêmes sont refusées sans débordement
+    let max = i64::MAX;
+    let uri = format!("/notations/range?server=red&from_week=0&to_week={}", max);
+    let (status, body) = get_json(&client, &uri, "k1").await;
+    assert_eq!((status, body["code"].clone()), (400, json!("range_too_large")));
+    let uri = format!("/notations/range?server=red&from_week=-1&to_week={}", max);
+    let (status, body) = get_json(&client, &uri, "k1").await;
+    assert_eq!((status, body["code"].clone()), (400, json!("invalid_parameter")));
+    let uri = format!("/notations/range?server=red&from_week={}&to_week={}", i64::MIN, max);
+    let (status, _) = get_json(&client, &uri, "k1").await;
+    assert_eq!(status, 400);
+}
+
+#[rocket::async_test]
+async fn old_history_samples_are_pruned_on_each_collection() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/hdv/"))
+        .respond_with(json_response(json!([]), Duration::ZERO))
+        .mount(&upstream)
+        .await;
+    let mut config = test_config(&upstream);
+    config.collector_api_keys = vec!["collector".to_string()];
+    config.hdv_history_interval = 1;
+    config.playercount_history_interval = 0;
+    config.history_retention_days = 30;
+    let client = start_proxy(config).await;
+    let history = client.rocket().state::<Arc<History>>().expect("managed history");
+    let old = Utc::now() - chrono::Duration::days(40);
+    let recent = Utc::now() - chrono::Duration::days(2);
+    let listing = || NormalizedListing {
+        item: "dirt".to_string(),
+        price: 1.0,
+        quantity: 1,
+        seller: None,
+    };
+    history.record_hdv(Server::Red, old, &[listing()]).unwrap();
+    history.record_hdv(Server::Red, recent, &[listing()]).unwrap();
+    history.record_player_counts(old, &[(Server::Red, 10)]).unwrap();
+    history.record_player_counts(recent, &[(Server::Red, 20)]).unwrap();
+
+    // Les relevés de plus de 30 jours disparaissent à la prochaine collecte
+    let window = format!("from={}", (old - chrono::Duration::days(1)).format("%Y-%m-%d"));
+    let started = Instant::now();
+    let volume = loop {
+        let (_, body) = get_json(&client, &format!("/hdv/red/volume?{}", window), "").await;
+        let recorded: Vec<serde_json::Value> = body["points"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|point| point["listings"] == json!(1))
+            .cloned()
+            .collect();
+        if recorded.len() < 2 || started.elapsed() > Duration::from_secs(5) {
+            break recorded;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert_eq!(volume.len(), 1);
+    let (_, body) = get_json(&client, &format!("/playercount/history?server=red&resolution=raw&{}", window), "").await;
+    let players: Vec<&serde_json::Value> = body["points"].as_array().unwrap().iter().map(|point| &point["peak"]).collect();
+    assert_eq!(players, vec![&json!(20)]);
+}
+
+#[test]
+fn retention_cutoffs_outside_the_date_range_disable_pruning() {
+    let now = Utc.with_ymd_and_hms(2024, 6, 30, 12, 0, 0).unwrap();
+    assert_eq!(retention_cutoff(now, 30), Some(Utc.with_ymd_and_hms(2024, 5, 31, 12, 0, 0).unwrap()));
+    for retention_days in [i32::MAX as u64, i64::MAX as u64, u64::MAX] {
+        assert_eq!(retention_cutoff(now, retention_days), None);
+    }
+}
+
+#[rocket::async_test]
+async fn a_cached_response_collected_twice_is_recorded_once() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/hdv/"))
+        .respond_with(json_response(json!([{"item": "dirt", "price": 10}]), Duration::ZERO))
+        .mount(&upstream)
+        .await;
+    mount_json(&upstream, "/playercount", json!({"red": 12})).await;
+    let mut config = test_config(&upstream);
+    config.collector_api_keys = vec!["collector".to_string()];
+    config.hdv_history_interval = 1;
+    config.playercount_history_interval = 1;
+    let client = start_proxy(config).await;
+
+    // Les collectes suivantes relisent la même entrée du cache, avec un âge différent
+    tokio::time::sleep(Duration::from_millis(2600)).await;
+
+    let (_, body) = get_json(&client, "/hdv/red/volume", "").await;
+    assert_eq!(body["points"].as_array().unwrap().len(), 1, "{}", body);
+    let (_, body) = get_json(&client, "/hdv/red/stats?item=dirt", "").await;
+    assert_eq!(body["listings"], json!(1));
+    let (_, body) = get_json(&client, "/playercount/history?server=red&resolution=raw", "").await;
+    assert_eq!(body["points"].as_array().unwrap().len(), 1, "{}", body);
+}